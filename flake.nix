@@ -69,6 +69,16 @@
             description = "config.toml file used by recogedor.";
           };
 
+          credentials = mkOption {
+            type = types.attrsOf types.str;
+            default = {};
+            example = {fox = "/run/secrets/imap-fox";};
+            description = ''
+              Secret files to pass to the service with systemd's LoadCredential.  Each is readable
+              by recogedor at /run/credentials/recogedor.service/<name>, e.g. for pass_file.
+            '';
+          };
+
           package = mkOption {
             type = types.package;
             default = self.packages.${system}.default;
@@ -85,9 +95,11 @@
 
             serviceConfig = {
               DynamicUser = "yes";
+              StateDirectory = "recogedor";
               ExecStart = "${cfg.package}/bin/recogedor --config ${configFile}";
               Restart = "on-failure";
               RestartSec = "5s";
+              LoadCredential = lib.mapAttrsToList (name: path: "${name}:${path}") cfg.credentials;
             };
             environment = lib.optionalAttrs (cfg.logLevel != null) {
               RUST_LOG = cfg.logLevel;
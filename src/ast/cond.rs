@@ -1,13 +1,23 @@
-use anyhow::{bail, Context, Result};
-use lexpr::Value;
+use anyhow::{bail, Result};
+use lexpr::datum::Ref;
 use std::fmt::{self, Display, Formatter};
 
-use super::value::{Flag, RecipientPattern};
+use super::form::Form;
+use super::span::{Origin, Spanned, SyntaxError};
+use super::value::{Flag, HeaderPattern, RecipientPattern};
 
 pub(crate) enum Cond {
-    Or(Vec<Cond>),
+    Or(Vec<Spanned<Cond>>),
+    And(Vec<Spanned<Cond>>),
+    Not(Box<Spanned<Cond>>),
     Flagged(Flag),
     ReceivedBy(RecipientPattern),
+    ReceivedByRegex(String),
+    SentBy(RecipientPattern),
+    Sender(RecipientPattern),
+    ReplyTo(RecipientPattern),
+    Header(HeaderPattern),
+    Seen,
 }
 
 impl Display for Cond {
@@ -22,30 +32,103 @@ impl Display for Cond {
                 f.write_str(")")?;
                 Ok(())
             }
+            Cond::And(cx) => {
+                f.write_str("(and")?;
+                for c in cx {
+                    f.write_str(" ")?;
+                    c.fmt(f)?;
+                }
+                f.write_str(")")?;
+                Ok(())
+            }
+            Cond::Not(c) => write!(f, "(not {})", c),
             Cond::Flagged(fl) => write!(f, "(flagged {:?})", fl.0),
             Cond::ReceivedBy(p) => write!(f, "(received-by {})", p),
+            Cond::ReceivedByRegex(re) => write!(f, "(received-by-regex {:?})", re),
+            Cond::SentBy(p) => write!(f, "(sent-by {})", p),
+            Cond::Sender(p) => write!(f, "(sender {})", p),
+            Cond::ReplyTo(p) => write!(f, "(reply-to {})", p),
+            Cond::Header(hp) => write!(f, "(header {:?} {:?})", hp.name, hp.regex),
+            Cond::Seen => f.write_str("(seen?)"),
         }
     }
 }
 
 impl Cond {
-    pub(super) fn from_sexp(sexp: &Value) -> Result<Cond> {
-        let vec = sexp.to_vec().context("?")?;
-        match vec.get(0).context("?")?.as_symbol().context("?")? {
-            "or" => Ok(Cond::Or(
-                vec.get(1..)
-                    .context("?")?
-                    .iter()
-                    .map(Cond::from_sexp)
-                    .collect::<Result<_>>()?,
-            )),
-            "flagged" => Ok(Cond::Flagged(
-                vec.get(1).context("?")?.as_str().context("?")?.into(),
-            )),
-            "received-by" => Ok(Cond::ReceivedBy(
-                vec.get(1).context("?")?.as_str().context("?")?.try_into()?,
+    pub(super) fn from_datum(datum: Ref<'_>, origin: &Origin) -> Result<Spanned<Cond>> {
+        let form = Form::new(datum, origin, "a condition")?;
+        let conds = |form: &Form| {
+            form.args()
+                .iter()
+                .map(|&c| Cond::from_datum(c, origin))
+                .collect::<Result<_>>()
+        };
+        let pattern = |form: &Form| -> Result<RecipientPattern> {
+            let s = form.str_arg(0, "a recipient pattern string")?;
+            s.try_into().map_err(|err| {
+                form.error(
+                    form.args()[0],
+                    format!("`{}` expects a recipient pattern ({})", form.head, err),
+                )
+                .into()
+            })
+        };
+        let node = match form.head {
+            "or" => {
+                form.arity(1, usize::MAX)?;
+                Cond::Or(conds(&form)?)
+            }
+            "and" => {
+                form.arity(1, usize::MAX)?;
+                Cond::And(conds(&form)?)
+            }
+            "not" => {
+                form.arity(1, 1)?;
+                Cond::Not(Box::new(Cond::from_datum(form.args()[0], origin)?))
+            }
+            "flagged" => {
+                form.arity(1, 1)?;
+                Cond::Flagged(form.str_arg(0, "a flag string")?.into())
+            }
+            "received-by" => {
+                form.arity(1, 1)?;
+                Cond::ReceivedBy(pattern(&form)?)
+            }
+            "received-by-regex" => {
+                form.arity(1, 1)?;
+                Cond::ReceivedByRegex(form.str_arg(0, "a regex string")?.into())
+            }
+            "sent-by" => {
+                form.arity(1, 1)?;
+                Cond::SentBy(pattern(&form)?)
+            }
+            "sender" => {
+                form.arity(1, 1)?;
+                Cond::Sender(pattern(&form)?)
+            }
+            "reply-to" => {
+                form.arity(1, 1)?;
+                Cond::ReplyTo(pattern(&form)?)
+            }
+            "header" => {
+                form.arity(2, 2)?;
+                Cond::Header(HeaderPattern {
+                    name: form.str_arg(0, "a header name string")?.into(),
+                    regex: form.str_arg(1, "a regex string")?.into(),
+                })
+            }
+            "seen?" => {
+                form.arity(0, 0)?;
+                Cond::Seen
+            }
+            s => bail!(SyntaxError::at(
+                form.span,
+                format!("unknown condition `{}`", s)
             )),
-            s => bail!("unknown (in Cond): {:?}", s),
-        }
+        };
+        Ok(Spanned {
+            node,
+            span: form.span,
+        })
     }
 }
@@ -0,0 +1,121 @@
+use anyhow::Result;
+use lexpr::{datum::Ref, Value};
+
+use super::span::{Origin, Span, SyntaxError};
+
+// A list form like (head arg ...), with the helpers Stmt and Cond use to pick their arguments
+// apart.
+pub(super) struct Form<'a> {
+    pub(super) head: &'a str,
+    pub(super) span: Span,
+    pub(super) origin: &'a Origin,
+    args: Vec<Ref<'a>>,
+}
+
+impl<'a> Form<'a> {
+    // kind is what the form is meant to be, e.g. "a statement".
+    pub(super) fn new(datum: Ref<'a>, origin: &'a Origin, kind: &str) -> Result<Form<'a>> {
+        let span = origin.span(datum.span());
+        let expected = || SyntaxError {
+            message: format!("expected {}", kind),
+            span,
+            got: Some(describe(datum.value())),
+        };
+        if !datum.value().is_list() {
+            return Err(expected().into());
+        }
+        let mut items = datum.list_iter().ok_or_else(expected)?;
+        let head = items.next().ok_or_else(expected)?;
+        let Some(name) = head.value().as_symbol() else {
+            return Err(SyntaxError {
+                message: format!("expected {} to start with a symbol", kind),
+                span: origin.span(head.span()),
+                got: Some(describe(head.value())),
+            }
+            .into());
+        };
+        Ok(Form {
+            head: name,
+            span,
+            origin,
+            args: items.collect(),
+        })
+    }
+
+    pub(super) fn args(&self) -> &[Ref<'a>] {
+        &self.args
+    }
+
+    pub(super) fn arity(&self, min: usize, max: usize) -> Result<()> {
+        let n = self.args.len();
+        if n >= min && n <= max {
+            return Ok(());
+        }
+        let expects = match (min, max) {
+            (0, 0) => "no arguments".to_string(),
+            (1, 1) => "1 argument".to_string(),
+            (min, max) if min == max => format!("{} arguments", min),
+            (min, usize::MAX) => format!("at least {} argument{}", min, plural(min)),
+            (min, max) => format!("{} to {} arguments", min, max),
+        };
+        Err(SyntaxError {
+            message: format!("`{}` expects {}", self.head, expects),
+            span: self.span,
+            got: Some(n.to_string()),
+        }
+        .into())
+    }
+
+    pub(super) fn str_arg(&self, ix: usize, what: &str) -> Result<&'a str> {
+        let arg = self.arg(ix, what)?;
+        arg.value().as_str().ok_or_else(|| {
+            self.error(arg, format!("`{}` expects {}", self.head, what))
+                .into()
+        })
+    }
+
+    pub(super) fn arg(&self, ix: usize, what: &str) -> Result<Ref<'a>> {
+        match self.args.get(ix) {
+            Some(arg) => Ok(*arg),
+            None => Err(SyntaxError {
+                message: format!("`{}` expects {}", self.head, what),
+                span: self.span,
+                got: Some("nothing".to_string()),
+            }
+            .into()),
+        }
+    }
+
+    pub(super) fn error(&self, arg: Ref<'_>, message: String) -> SyntaxError {
+        SyntaxError {
+            message,
+            span: self.origin.span(arg.span()),
+            got: Some(describe(arg.value())),
+        }
+    }
+}
+
+fn describe(value: &Value) -> String {
+    let kind = match value {
+        Value::Nil => return "nil".to_string(),
+        Value::Null => return "empty list".to_string(),
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::Char(_) => "character",
+        Value::String(_) => return format!("string {}", value),
+        Value::Symbol(_) => "symbol",
+        Value::Keyword(_) => "keyword",
+        Value::Bytes(_) => "byte string",
+        Value::Cons(_) => "list",
+        Value::Vector(_) => "vector",
+    };
+    format!("{} `{}`", kind, value)
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
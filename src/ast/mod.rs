@@ -1,7 +1,10 @@
 mod cond;
+mod form;
+mod span;
 mod stmt;
 mod value;
 
 pub(crate) use cond::Cond;
+pub(crate) use span::{Origin, Span, Spanned, SyntaxError};
 pub(crate) use stmt::Stmt;
-pub(crate) use value::{Destination, Flag, RecipientPattern};
+pub(crate) use value::{Destination, Flag, HeaderPattern, RecipientPattern};
@@ -0,0 +1,179 @@
+use lexpr::{datum, parse};
+use std::{
+    error,
+    fmt::{self, Display, Formatter},
+    ops::Deref,
+};
+
+/// A 1-based line and column in the config file.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct Pos {
+    pub(crate) line: usize,
+    pub(crate) col: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Span {
+    pub(crate) start: Pos,
+    pub(crate) end: Pos,
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} col {}", self.start.line, self.start.col)
+    }
+}
+
+/// Where each byte of the script came from in the config file, so positions lexpr gives relative
+/// to the script can be given in the file, escapes and line continuations notwithstanding.
+pub(crate) struct Origin {
+    // The file's lines, for showing.
+    lines: Vec<String>,
+    // The (1-based) line and (0-based) byte column in the file of each byte of the script.
+    positions: Vec<(usize, usize)>,
+    // The index in positions of the start of each of the script's lines.
+    starts: Vec<usize>,
+    // Just after the script's last non-blank byte.
+    end: (usize, usize),
+    // Whether the file is just the script, when it couldn't be found in the config.
+    relative: bool,
+}
+
+impl Origin {
+    /// Positions relative to the script itself.
+    pub(crate) fn script(text: &str) -> Origin {
+        let mut positions = vec![];
+        for (ix, line) in text.split('\n').enumerate() {
+            positions.extend((0..=line.len()).map(|col| (ix + 1, col)));
+        }
+        positions.truncate(text.len());
+        Origin::new(text, text, positions, true)
+    }
+
+    /// The script as decoded from a string in file, given where each of its bytes came from.
+    pub(crate) fn file(file: &str, text: &str, positions: Vec<(usize, usize)>) -> Origin {
+        Origin::new(file, text, positions, false)
+    }
+
+    fn new(file: &str, text: &str, positions: Vec<(usize, usize)>, relative: bool) -> Origin {
+        let mut starts = vec![0];
+        starts.extend(
+            text.bytes()
+                .enumerate()
+                .filter(|&(_, c)| c == b'\n')
+                .map(|(ix, _)| ix + 1),
+        );
+        let end = text
+            .bytes()
+            .rposition(|c| !c.is_ascii_whitespace())
+            .map_or((1, 0), |ix| (positions[ix].0, positions[ix].1 + 1));
+        Origin {
+            lines: file.lines().map(str::to_string).collect(),
+            positions,
+            starts,
+            end,
+            relative,
+        }
+    }
+
+    // line is 1-based and col 0-based, as lexpr has them.  Past the end of the script (as for an
+    // unexpected EOF) is just after its last non-blank character.
+    fn pos(&self, line: usize, col: usize) -> Pos {
+        let ix = self
+            .starts
+            .get(line.saturating_sub(1))
+            .map(|start| start + col)
+            .filter(|&ix| ix < self.positions.len());
+        let (line, col) = ix.map_or(self.end, |ix| self.positions[ix]);
+        Pos { line, col: col + 1 }
+    }
+
+    pub(crate) fn span(&self, span: datum::Span) -> Span {
+        let (start, end) = (span.start(), span.end());
+        Span {
+            start: self.pos(start.line(), start.column()),
+            end: self.pos(end.line(), end.column()),
+        }
+    }
+
+    pub(crate) fn parse_error(&self, err: &parse::Error) -> SyntaxError {
+        // lexpr's message ends with its own (script-relative) location.
+        let message = err.to_string();
+        let message = match message.rsplit_once(" at line ") {
+            Some((message, _)) => message.to_string(),
+            None => message,
+        };
+        let pos = match err.location() {
+            Some(loc) => self.pos(loc.line(), loc.column().saturating_sub(1)),
+            None => self.pos(1, 0),
+        };
+        SyntaxError {
+            message,
+            span: Span {
+                start: pos,
+                end: pos,
+            },
+            got: None,
+        }
+    }
+
+    /// The file's line, for showing.
+    pub(crate) fn line(&self, line: usize) -> Option<&str> {
+        self.lines.get(line.checked_sub(1)?).map(|l| l.as_str())
+    }
+
+    /// Whether positions are in the script rather than the config file.
+    pub(crate) fn relative(&self) -> bool {
+        self.relative
+    }
+}
+
+pub(crate) struct Spanned<T> {
+    pub(crate) node: T,
+    pub(crate) span: Span,
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+impl<T: Display> Display for Spanned<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.node.fmt(f)
+    }
+}
+
+/// An error in the script, e.g. "`append!` expects a destination string at line 7 col 5, got
+/// symbol `fox`".
+#[derive(Debug)]
+pub(crate) struct SyntaxError {
+    pub(crate) message: String,
+    pub(crate) span: Span,
+    pub(crate) got: Option<String>,
+}
+
+impl SyntaxError {
+    pub(crate) fn at(span: Span, message: impl Into<String>) -> SyntaxError {
+        SyntaxError {
+            message: message.into(),
+            span,
+            got: None,
+        }
+    }
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.span)?;
+        if let Some(got) = &self.got {
+            write!(f, ", got {}", got)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for SyntaxError {}
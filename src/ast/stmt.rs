@@ -1,17 +1,24 @@
-use anyhow::{bail, Context, Result};
-use lexpr::Value;
+use anyhow::{bail, Result};
+use lexpr::datum::Ref;
 use std::fmt::{self, Display, Formatter};
 
 use super::cond::Cond;
+use super::form::Form;
+use super::span::{Origin, Spanned, SyntaxError};
 use super::value::{Destination, Flag};
 
 pub(crate) enum Stmt {
-    If(Cond, Box<Stmt>, Option<Box<Stmt>>),
-    Do(Vec<Stmt>),
+    If(
+        Spanned<Cond>,
+        Box<Spanned<Stmt>>,
+        Option<Box<Spanned<Stmt>>>,
+    ),
+    Do(Vec<Spanned<Stmt>>),
     Append(Destination),
     Flag(Flag),
     Halt,
     Delete,
+    Remember,
 }
 
 impl Display for Stmt {
@@ -46,44 +53,58 @@ impl Stmt {
             Stmt::Flag(fl) => write!(f, "\n{}(flag! {:?})", " ".repeat(indent * INDENT), fl.0),
             Stmt::Halt => write!(f, "\n{}(halt!)", " ".repeat(indent * INDENT)),
             Stmt::Delete => write!(f, "\n{}(delete!)", " ".repeat(indent * INDENT)),
+            Stmt::Remember => write!(f, "\n{}(remember!)", " ".repeat(indent * INDENT)),
         }
     }
 
-    pub(crate) fn from_sexp(sexp: &Value) -> Result<Stmt> {
-        let vec = sexp.to_vec().context("stmt isn't cons")?;
-        let head = vec
-            .get(0)
-            .context("stmt cons empty")?
-            .as_symbol()
-            .context("stmt car isn't sym")?;
-        match head {
-            "if" => Ok(Stmt::If(
-                Cond::from_sexp(vec.get(1).context("'if' statement missing condition")?)?,
-                Box::new(Stmt::from_sexp(
-                    vec.get(2).context("'if' statement missing 'then'")?,
-                )?),
-                // Someone help me budget my family is dying
-                // Please help me vec.get(3).map my way through this.
-                match vec.get(3) {
-                    Some(s) => Some(Box::new(Stmt::from_sexp(s)?)),
-                    None => None,
-                },
-            )),
-            "do" => Ok(Stmt::Do(
-                vec[1..]
+    pub(crate) fn from_datum(datum: Ref<'_>, origin: &Origin) -> Result<Spanned<Stmt>> {
+        let form = Form::new(datum, origin, "a statement")?;
+        let node = match form.head {
+            "if" => {
+                form.arity(2, 3)?;
+                Stmt::If(
+                    Cond::from_datum(form.args()[0], origin)?,
+                    Box::new(Stmt::from_datum(form.args()[1], origin)?),
+                    match form.args().get(2) {
+                        Some(&s) => Some(Box::new(Stmt::from_datum(s, origin)?)),
+                        None => None,
+                    },
+                )
+            }
+            "do" => Stmt::Do(
+                form.args()
                     .iter()
-                    .map(Stmt::from_sexp)
+                    .map(|&s| Stmt::from_datum(s, origin))
                     .collect::<Result<Vec<_>>>()?,
+            ),
+            "append!" => {
+                form.arity(1, 1)?;
+                Stmt::Append(form.str_arg(0, "a destination string")?.into())
+            }
+            "flag!" => {
+                form.arity(1, 1)?;
+                Stmt::Flag(form.str_arg(0, "a flag string")?.into())
+            }
+            "halt!" => {
+                form.arity(0, 0)?;
+                Stmt::Halt
+            }
+            "delete!" => {
+                form.arity(0, 0)?;
+                Stmt::Delete
+            }
+            "remember!" => {
+                form.arity(0, 0)?;
+                Stmt::Remember
+            }
+            s => bail!(SyntaxError::at(
+                form.span,
+                format!("unknown statement `{}`", s)
             )),
-            "append!" => Ok(Stmt::Append(
-                vec.get(1).context("?")?.as_str().context("?")?.into(),
-            )),
-            "flag!" => Ok(Stmt::Flag(
-                vec.get(1).context("?")?.as_str().context("?")?.into(),
-            )),
-            "halt!" => Ok(Stmt::Halt),
-            "delete!" => Ok(Stmt::Delete),
-            s => bail!("unknown (in Stmt): {:?}", s),
-        }
+        };
+        Ok(Spanned {
+            node,
+            span: form.span,
+        })
     }
 }
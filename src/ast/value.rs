@@ -16,6 +16,7 @@ impl From<&str> for Flag {
     }
 }
 
+#[derive(Clone, PartialEq)]
 pub(crate) struct RecipientPattern {
     pub(crate) mailbox: Option<Vec<u8>>,
     pub(crate) plus: Option<Vec<u8>>,
@@ -54,11 +55,36 @@ impl RecipientPattern {
         true
     }
 
-    fn parts_equal(a: &[u8], b: &[u8]) -> bool {
-        a.iter()
-            .map(Self::part_lower)
-            .zip(b.iter().map(Self::part_lower))
-            .all(|(x, y)| x == y)
+    // Case-insensitive glob match: `*` matches any run of bytes, `?` any single byte.
+    fn parts_equal(pattern: &[u8], part: &[u8]) -> bool {
+        let (mut p, mut r) = (0, 0);
+        let mut backtrack: Option<(usize, usize)> = None;
+
+        while r < part.len() {
+            match pattern.get(p) {
+                Some(b'*') => {
+                    backtrack = Some((p, r));
+                    p += 1;
+                    continue;
+                }
+                Some(&c) if c == b'?' || Self::part_lower(&c) == Self::part_lower(&part[r]) => {
+                    p += 1;
+                    r += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match backtrack {
+                Some((bp, br)) => {
+                    backtrack = Some((bp, br + 1));
+                    p = bp + 1;
+                    r = br + 1;
+                }
+                None => return false,
+            }
+        }
+
+        pattern[p..].iter().all(|&c| c == b'*')
     }
 
     fn part_lower(c: &u8) -> u8 {
@@ -91,7 +117,7 @@ impl std::convert::TryFrom<&str> for RecipientPattern {
     type Error = Error;
     fn try_from(s: &str) -> Result<RecipientPattern> {
         static RE: Lazy<Regex> = Lazy::new(|| {
-            Regex::new(r"\A(?<mailbox>[^+@]+)?(?:\+(?<plus>[^@]+))?@(?<host>.+)?\z").unwrap()
+            Regex::new(r"\A(?<mailbox>[^+@]+)?(?:\+(?<plus>[^@]*))?@(?<host>.+)?\z").unwrap()
         });
         let captures = RE.captures(s.as_bytes()).context("pattern syntax error")?;
 
@@ -111,6 +137,11 @@ impl std::convert::TryFrom<&str> for RecipientPattern {
     }
 }
 
+pub(crate) struct HeaderPattern {
+    pub(crate) name: String,
+    pub(crate) regex: String,
+}
+
 pub(crate) struct Destination(pub(crate) String);
 
 impl From<&str> for Destination {
@@ -118,3 +149,72 @@ impl From<&str> for Destination {
         Destination(s.into())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pattern(s: &str) -> RecipientPattern {
+        RecipientPattern::try_from(s).unwrap()
+    }
+
+    fn recipient(s: &str) -> Recipient {
+        let (mailbox, host) = s.split_once('@').unwrap();
+        Recipient {
+            mailbox: mailbox.as_bytes().to_vec(),
+            host: host.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn globs() {
+        let glob = |p: &str, s: &str| RecipientPattern::parts_equal(p.as_bytes(), s.as_bytes());
+        assert!(glob("fox", "fox"));
+        assert!(glob("fox", "FOX"));
+        assert!(!glob("fox", "fo"));
+        assert!(!glob("fo", "fox"));
+        assert!(glob("f?x", "fix"));
+        assert!(!glob("f?x", "fx"));
+        assert!(glob("*", ""));
+        assert!(glob("*", "anything"));
+        assert!(glob("f*", "f"));
+        assert!(glob("*x", "fox"));
+        assert!(!glob("*x", "foxy"));
+        // Needs to backtrack past the first "ab".
+        assert!(glob("*ab*abc", "xabyabzabc"));
+        assert!(glob("a*b*c", "aXbYbZc"));
+        assert!(!glob("a*b*c", "aXbYbZ"));
+        assert!(glob("a**?", "ab"));
+        assert!(!glob("a**?", "a"));
+    }
+
+    #[test]
+    fn parsing() {
+        let p = pattern("+@den.com");
+        assert_eq!(p.mailbox, None);
+        assert_eq!(p.plus, Some(vec![]));
+        assert_eq!(p.host, Some(b"den.com".to_vec()));
+
+        let p = pattern("fox+lists@");
+        assert_eq!(p.mailbox, Some(b"fox".to_vec()));
+        assert_eq!(p.plus, Some(b"lists".to_vec()));
+        assert_eq!(p.host, None);
+
+        assert!(RecipientPattern::try_from("@").is_err());
+        assert!(RecipientPattern::try_from("fox").is_err());
+    }
+
+    #[test]
+    fn matching() {
+        assert!(pattern("fox@den.com").matches(&recipient("fox@den.com")));
+        assert!(pattern("fox@den.com").matches(&recipient("Fox+lists@DEN.com")));
+        assert!(!pattern("fox@den.com").matches(&recipient("foxy@den.com")));
+        assert!(!pattern("fox@den.com").matches(&recipient("fox@den.co")));
+        assert!(pattern("+@den.com").matches(&recipient("fox@den.com")));
+        assert!(!pattern("+@den.com").matches(&recipient("fox+x@den.com")));
+        assert!(pattern("+lists@").matches(&recipient("fox+lists@den.com")));
+        assert!(!pattern("+lists@").matches(&recipient("fox@den.com")));
+        assert!(pattern("*@*.den.com").matches(&recipient("fox@mx.den.com")));
+        assert!(!pattern("*@*.den.com").matches(&recipient("fox@den.com")));
+    }
+}
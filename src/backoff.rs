@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+const INITIAL: Duration = Duration::from_secs(1);
+const MAX: Duration = Duration::from_secs(5 * 60);
+
+// Exponential backoff with jitter: each delay is uniformly chosen from the upper half of the
+// current window, and the window doubles each time up to MAX.
+pub(crate) struct Backoff {
+    window: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Backoff {
+        Backoff { window: INITIAL }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.window = INITIAL;
+    }
+
+    pub(crate) fn next(&mut self) -> Duration {
+        let window = self.window;
+        self.window = (self.window * 2).min(MAX);
+        window / 2 + window.mul_f64(rand::random::<f64>() / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn windows() {
+        let mut backoff = Backoff::new();
+        let mut window = INITIAL;
+        for _ in 0..20 {
+            let delay = backoff.next();
+            assert!(delay >= window / 2 && delay <= window);
+            window = (window * 2).min(MAX);
+        }
+        assert_eq!(window, MAX);
+
+        backoff.reset();
+        assert!(backoff.next() <= INITIAL);
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::{collections::HashSet, fs, path::Path};
+
+use crate::config::Config;
+use crate::endpoint::Message;
+use crate::header;
+use crate::recorder::{self, Action, ScratchState};
+
+/// A [[process.test]] case: a mail item, and what the script is expected to do with it.
+pub(crate) struct TestCase {
+    name: String,
+    body: Vec<u8>,
+    flags: HashSet<String>,
+    recipients: Vec<(String, String)>,
+    folder: Option<String>,
+    expect: Vec<Action>,
+}
+
+impl TestCase {
+    // Paths are relative to the config file's directory.
+    pub(crate) fn from_config(ix: usize, value: &toml::Value, base: &Path) -> Result<TestCase> {
+        let table = value.as_table().context("test should be table?")?;
+        let name = match table.get("name") {
+            Some(v) => v
+                .as_str()
+                .context("test name should be string?")?
+                .to_string(),
+            None => format!("#{}", ix + 1),
+        };
+        let which = format!("test {}", name);
+
+        let body = match (table.get("message"), table.get("path")) {
+            (Some(message), None) => message
+                .as_str()
+                .with_context(|| format!("{} message should be string?", which))?
+                .as_bytes()
+                .to_vec(),
+            (None, Some(path)) => {
+                let path = base.join(
+                    path.as_str()
+                        .with_context(|| format!("{} path should be string?", which))?,
+                );
+                fs::read(&path)
+                    .with_context(|| format!("{} can't read mail from {:?}", which, path))?
+            }
+            _ => bail!("{} needs exactly one of message and path", which),
+        };
+
+        let strings = |key: &str| -> Result<Vec<String>> {
+            let Some(v) = table.get(key) else {
+                return Ok(vec![]);
+            };
+            v.as_array()
+                .with_context(|| format!("{} {} should be list?", which, key))?
+                .iter()
+                .map(|s| {
+                    s.as_str()
+                        .map(str::to_string)
+                        .with_context(|| format!("{} {} should be strings?", which, key))
+                })
+                .collect()
+        };
+
+        let flags = strings("flags")?.into_iter().collect();
+        let recipients = strings("recipients")?
+            .iter()
+            .flat_map(|r| header::addresses(r))
+            .collect();
+        let folder = match table.get("folder") {
+            Some(v) => Some(
+                v.as_str()
+                    .with_context(|| format!("{} folder should be string?", which))?
+                    .to_string(),
+            ),
+            None => None,
+        };
+        if table.get("expect").is_none() {
+            bail!("{} lacks expect", which);
+        }
+        let expect = strings("expect")?
+            .iter()
+            .map(|a| a.parse())
+            .collect::<Result<_>>()
+            .with_context(|| format!("{} expect", which))?;
+
+        Ok(TestCase {
+            name,
+            body,
+            flags,
+            recipients,
+            folder,
+            expect,
+        })
+    }
+}
+
+/// Runs every [[process.test]] case, printing a diff of the actions for each one that fails.
+pub(crate) async fn run(config: &Config) -> Result<()> {
+    let mut failed = 0;
+    for test in &config.tests {
+        let mut mail = Message::from_rfc822(1, test.body.clone(), test.flags.clone());
+        for (mailbox, host) in &test.recipients {
+            mail.add_recipient(mailbox, host);
+        }
+        let folder = test
+            .folder
+            .as_ref()
+            .or(config.folders.first())
+            .map_or("INBOX", |f| f.as_str());
+        // Each case starts from an empty state, so they don't depend on each other.
+        let scratch = config
+            .ir
+            .uses_state()
+            .then(ScratchState::open)
+            .transpose()?;
+
+        let actual = recorder::simulate(
+            &config.ir,
+            folder,
+            scratch.as_ref().map(|s| &s.state),
+            &mail,
+        )
+        .await
+        .with_context(|| format!("running test {}", test.name))?;
+        if actual == test.expect {
+            info!("test {} ok", test.name);
+            continue;
+        }
+
+        failed += 1;
+        println!("test {} failed (- expected, + actual):", test.name);
+        for line in diff(&test.expect, &actual) {
+            println!("  {}", line);
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} of {} script tests failed", failed, config.tests.len());
+    }
+    if !config.tests.is_empty() {
+        info!("{} script tests passed", config.tests.len());
+    }
+    Ok(())
+}
+
+// A line diff by longest common subsequence; action lists are short.
+fn diff(expected: &[Action], actual: &[Action]) -> Vec<String> {
+    let (n, m) = (expected.len(), actual.len());
+    let mut lcs = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut result = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            result.push(format!("  {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            result.push(format!("- {}", expected[i]));
+            i += 1;
+        } else {
+            result.push(format!("+ {}", actual[j]));
+            j += 1;
+        }
+    }
+    result
+}
@@ -1,15 +1,31 @@
-use anyhow::{Context, Result};
-use std::{collections::HashMap, fs, path::Path};
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use toml::Table;
 
+use crate::ast::Origin;
+use crate::check::TestCase;
 use crate::endpoint::Endpoint;
 use crate::ir::IR;
 use crate::script;
+use crate::state::State;
 
 pub(crate) struct Config {
     pub(crate) src: Endpoint,
     pub(crate) folders: Vec<String>,
+    pub(crate) full_rescan: bool,
     pub(crate) ir: IR,
+    pub(crate) tests: Vec<TestCase>,
+    state_dir: Option<PathBuf>,
+    // Opened by open_state, once it's known whether it'll be written to.
+    pub(crate) state: Option<State>,
+    pub(crate) grace_period: Duration,
 }
 
 pub(crate) fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
@@ -20,6 +36,9 @@ pub(crate) fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
 
     let cfg_src = top.get("src").context("config lacks src")?;
     let src = Endpoint::from_config("src", cfg_src)?;
+    if let Endpoint::Mbox(_) = src {
+        bail!("src mbox not supported as a source");
+    }
     let folders_arr = cfg_src
         .get("folders")
         .context("src lacks folders")?
@@ -35,6 +54,17 @@ pub(crate) fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
         );
     }
 
+    let full_rescan = match cfg_src.get("full_rescan") {
+        Some(v) => v.as_bool().context("full_rescan should be bool?")?,
+        None => false,
+    };
+
+    // Off by default: it relies on the server's address searches matching substrings.
+    let prefilter = match cfg_src.get("prefilter") {
+        Some(v) => v.as_bool().context("prefilter should be bool?")?,
+        None => false,
+    };
+
     let mut dests = HashMap::<String, Endpoint>::new();
     let cfg_dests = top
         .get("dest")
@@ -58,7 +88,222 @@ pub(crate) fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
         .as_str()
         .context("process script should be string?")?;
 
-    let ir = script::compile(script_text, dests)?;
+    let mut ir = script::compile(script_text, &script_origin(&toml, script_text), dests)?;
+    if let Some(v) = process.get("trace") {
+        ir.set_trace(v.as_bool().context("process trace should be bool?")?);
+    }
+    // Maildir sources read everything anyway.
+    if !prefilter || matches!(src, Endpoint::Maildir(_)) {
+        ir.disable_search();
+    }
+
+    let mut tests = vec![];
+    if let Some(v) = process.get("test") {
+        let base = path.parent().unwrap_or(Path::new("."));
+        for (ix, test) in v
+            .as_array()
+            .context("process tests should be list?")?
+            .iter()
+            .enumerate()
+        {
+            tests.push(TestCase::from_config(ix, test, base)?);
+        }
+    }
+
+    let state_dir = match top.get("state_dir") {
+        Some(v) => Some(v.as_str().context("state_dir should be string?")?.into()),
+        None => None,
+    };
+    if state_dir.is_none() && ir.uses_state() {
+        bail!("script uses seen?/remember! but config lacks state_dir");
+    }
+
+    let grace_period = match top.get("grace_period") {
+        Some(v) => Duration::from_secs(
+            v.as_integer()
+                .context("grace_period should be integer?")?
+                .try_into()
+                .context("grace_period should be non-negative")?,
+        ),
+        None => Duration::from_secs(30),
+    };
+
+    Ok(Config {
+        src,
+        folders,
+        full_rescan,
+        ir,
+        tests,
+        state_dir,
+        state: None,
+        grace_period,
+    })
+}
 
-    Ok(Config { src, folders, ir })
+impl Config {
+    /// Opens state_dir, if given.  Read-only, nothing is created or changed, so it's safe to do
+    /// alongside a running instance.
+    pub(crate) fn open_state(&mut self, read_only: bool) -> Result<()> {
+        let Some(dir) = &self.state_dir else {
+            return Ok(());
+        };
+        self.state = Some(if read_only {
+            State::open_read_only(dir)?
+        } else {
+            State::open(dir)?
+        });
+        Ok(())
+    }
+}
+
+// Where process.script's text is in the config file, so errors in it can be given file positions.
+// toml::Table doesn't keep spans, so look for the key ourselves and decode the string again, noting
+// where each byte came from.  If that doesn't give the same script, positions are relative to it.
+fn script_origin(toml: &str, script: &str) -> Origin {
+    static RE: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r#"(?ms)^[ \t]*\[process\].*?^[ \t]*script[ \t]*=[ \t]*("""|'''|"|')"#).unwrap()
+    });
+    let decoded = RE
+        .captures(toml)
+        .and_then(|captures| decode_string(toml, captures.get(0).unwrap().end(), &captures[1]));
+    match decoded {
+        Some(decoded) if decoded.iter().map(|&(byte, _)| byte).eq(script.bytes()) => Origin::file(
+            toml,
+            script,
+            decoded.into_iter().map(|(_, pos)| pos).collect(),
+        ),
+        _ => Origin::script(script),
+    }
+}
+
+// Decodes the string whose opening delimiter ends at start, returning each of its bytes with
+// the line (1-based) and byte column (0-based) it came from.  All of an escape's bytes come from
+// its backslash.
+fn decode_string(toml: &str, start: usize, delim: &str) -> Option<Vec<(u8, (usize, usize))>> {
+    let b = toml.as_bytes();
+    let line_starts: Vec<usize> = [0]
+        .into_iter()
+        .chain(toml.match_indices('\n').map(|(ix, _)| ix + 1))
+        .collect();
+    let at = |ix: usize| {
+        let line = line_starts.partition_point(|&s| s <= ix);
+        (line, ix - line_starts[line - 1])
+    };
+    let multi = delim.len() == 3;
+    let literal = delim.starts_with('\'');
+    let quote = delim.as_bytes()[0];
+
+    let mut decoded = vec![];
+    let mut ix = start;
+    // A newline straight after a multi-line string's opening delimiter isn't part of the string.
+    if multi {
+        if b[ix..].starts_with(b"\r\n") {
+            ix += 2;
+        } else if b[ix..].starts_with(b"\n") {
+            ix += 1;
+        }
+    }
+    loop {
+        let c = *b.get(ix)?;
+        if c == quote {
+            // Up to two quotes just before a multi-line string's closing delimiter are part of it.
+            let run = b[ix..].iter().take_while(|&&q| q == quote).count();
+            let end = !multi || run >= 3;
+            let content = if !multi {
+                0
+            } else if end {
+                run - 3
+            } else {
+                run
+            };
+            for k in 0..content {
+                decoded.push((quote, at(ix + k)));
+            }
+            if end {
+                return Some(decoded);
+            }
+            ix += run;
+            continue;
+        }
+        if c == b'\\' && !literal {
+            let hex = |len: usize| {
+                let code = u32::from_str_radix(toml.get(ix + 2..ix + 2 + len)?, 16).ok()?;
+                Some((char::from_u32(code)?, 2 + len))
+            };
+            let (escaped, len) = match *b.get(ix + 1)? {
+                b'b' => ('\u{8}', 2),
+                b't' => ('\t', 2),
+                b'n' => ('\n', 2),
+                b'f' => ('\u{c}', 2),
+                b'r' => ('\r', 2),
+                b'e' => ('\u{1b}', 2),
+                b'"' => ('"', 2),
+                b'\\' => ('\\', 2),
+                b'x' => hex(2)?,
+                b'u' => hex(4)?,
+                b'U' => hex(8)?,
+                _ if multi => {
+                    // A line-ending backslash trims the whitespace and newlines after it.
+                    let rest = &b[ix + 1..];
+                    let blanks = rest.iter().take_while(|&&c| matches!(c, b' ' | b'\t'));
+                    if !matches!(rest.get(blanks.count()), Some(b'\n' | b'\r')) {
+                        return None;
+                    }
+                    let skip = rest
+                        .iter()
+                        .take_while(|&&c| matches!(c, b' ' | b'\t' | b'\r' | b'\n'));
+                    ix += 1 + skip.count();
+                    continue;
+                }
+                _ => return None,
+            };
+            for &byte in escaped.encode_utf8(&mut [0; 4]).as_bytes() {
+                decoded.push((byte, at(ix)));
+            }
+            ix += len;
+            continue;
+        }
+        if c == b'\n' && !multi {
+            return None;
+        }
+        decoded.push((c, at(ix)));
+        ix += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The first line of the error compiling config's script.
+    fn error(config: &str) -> String {
+        let top = config.parse::<Table>().unwrap();
+        let script = top["process"]["script"].as_str().unwrap();
+        let err = script::compile(script, &script_origin(config, script), HashMap::new())
+            .err()
+            .unwrap();
+        err.to_string().lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn script_positions() {
+        let config = "[process]\nscript = '(append! fox)'\n";
+        assert!(error(config).ends_with("at line 2 col 20, got symbol `fox`"));
+
+        // Line continuations and escapes don't throw positions off.
+        let config = "[process]\nscript = \"\"\"\n(if (flagged \"a\") \\\n   (append! \"d\"))\n\
+                      (if (header \"S\" \"\\u00e9\\t\") (append! fox))\n\"\"\"\n";
+        assert!(error(config).ends_with("at line 5 col 38, got symbol `fox`"));
+
+        // EOF errors point just after the script's last datum.
+        let config = "[process]\nscript = \"\"\"\n(if (flagged \"a\")\n  (append! \"d\")\n\"\"\"\n";
+        assert_eq!(error(config), "EOF while parsing a list at line 4 col 16");
+    }
+
+    #[test]
+    fn script_positions_unknown() {
+        // Not found, so positions are within the script.
+        let config = "[process]\n\"script\" = '''\n\n(append! fox)'''\n";
+        assert!(error(config).ends_with("at line 2 col 10, got symbol `fox`"));
+    }
 }
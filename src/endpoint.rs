@@ -1,13 +1,20 @@
 use anyhow::{bail, Context, Error, Result};
 use async_imap::types::Flag;
 use async_trait::async_trait;
-use std::collections::HashSet;
+use once_cell::sync::OnceCell;
+use regex::{bytes, Regex};
+use std::{collections::HashSet, fmt};
 
-use crate::{ast::RecipientPattern, imap::ImapEndpoint};
+use crate::{
+    ast::RecipientPattern, header, imap::ImapEndpoint, ir::SearchKey, maildir::MaildirEndpoint,
+    mbox::MboxEndpoint, shutdown::Shutdown,
+};
 
 #[derive(Clone)]
 pub(crate) enum Endpoint {
-    Imap(ImapEndpoint),
+    Imap(Box<ImapEndpoint>),
+    Maildir(MaildirEndpoint),
+    Mbox(MboxEndpoint),
 }
 
 impl Endpoint {
@@ -20,10 +27,24 @@ impl Endpoint {
             .with_context(|| format!("{} config missing type", which))?
             .as_str()
             .with_context(|| format!("{} config type not string", which))?;
-        if tipo != "imap" {
-            bail!("unknown type {}", tipo);
+        match tipo {
+            "imap" => Ok(Endpoint::Imap(Box::new(ImapEndpoint::from_config(
+                which, table,
+            )?))),
+            "maildir" => Ok(Endpoint::Maildir(MaildirEndpoint::from_config(
+                which, table,
+            )?)),
+            "mbox" => Ok(Endpoint::Mbox(MboxEndpoint::from_config(which, table)?)),
+            _ => bail!("unknown type {}", tipo),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            Endpoint::Imap(ie) => ie.name(),
+            Endpoint::Maildir(me) => me.name(),
+            Endpoint::Mbox(me) => me.name(),
         }
-        Ok(Endpoint::Imap(ImapEndpoint::from_config(which, table)?))
     }
 
     pub(crate) async fn connect_source(&self) -> Result<Box<dyn SourceEndpoint>> {
@@ -32,6 +53,8 @@ impl Endpoint {
                 let iec = ie.connect().await?;
                 Ok(Box::new(iec))
             }
+            Endpoint::Maildir(me) => Ok(Box::new(me.connect())),
+            Endpoint::Mbox(_) => unreachable!("mbox source rejected by config"),
         }
     }
     pub(crate) async fn connect_destination(&self) -> Result<Box<dyn DestinationEndpoint>> {
@@ -40,18 +63,102 @@ impl Endpoint {
                 let iec = ie.connect().await?;
                 Ok(Box::new(iec))
             }
+            Endpoint::Maildir(me) => Ok(Box::new(me.connect())),
+            Endpoint::Mbox(me) => Ok(Box::new(me.connect())),
         }
     }
 }
 
 pub(crate) struct Message {
     pub(crate) uid: u32,
-    pub(crate) body: Vec<u8>,
+    body: OnceCell<Vec<u8>>,
+    pub(crate) message_id: Option<Vec<u8>>,
+    pub(crate) subject: Option<String>,
     flags: HashSet<String>,
     recipients: HashSet<Recipient>,
+    from: HashSet<Recipient>,
+    sender: HashSet<Recipient>,
+    reply_to: HashSet<Recipient>,
+    headers: OnceCell<Vec<(String, String)>>,
 }
 
 impl Message {
+    pub(crate) fn body(&self) -> Result<&[u8]> {
+        Ok(self.body.get().context("message body not loaded")?)
+    }
+
+    pub(crate) async fn load_body(&self, src: &mut Box<dyn SourceEndpoint>) -> Result<&[u8]> {
+        if self.body.get().is_none() {
+            let body = src.fetch_body(self.uid).await?;
+            _ = self.body.set(body);
+        }
+        self.body()
+    }
+
+    /// A message read from its raw RFC 822 text rather than fetched, with the envelope built from
+    /// its headers.
+    pub(crate) fn from_rfc822(uid: u32, body: Vec<u8>, flags: HashSet<String>) -> Message {
+        let headers = header::parse(&body);
+        let addresses = |names: &[&str]| -> HashSet<Recipient> {
+            headers
+                .iter()
+                .filter(|(n, _)| names.iter().any(|name| n.eq_ignore_ascii_case(name)))
+                .flat_map(|(_, v)| header::addresses(v))
+                .map(|(mailbox, host)| Recipient {
+                    mailbox: mailbox.into_bytes(),
+                    host: host.into_bytes(),
+                })
+                .collect()
+        };
+
+        let recipients = addresses(&["To", "Cc", "Bcc"]);
+        let from = addresses(&["From"]);
+        // As in the IMAP envelope, Sender and Reply-To default to From.
+        let sender = Some(addresses(&["Sender"]))
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| addresses(&["From"]));
+        let reply_to = Some(addresses(&["Reply-To"]))
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| addresses(&["From"]));
+        let message_id = headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case("Message-ID"))
+            .map(|(_, v)| v.as_bytes().to_vec());
+        let subject = headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case("Subject"))
+            .map(|(_, v)| v.clone());
+
+        Message {
+            uid,
+            body: OnceCell::with_value(body),
+            message_id,
+            subject,
+            flags,
+            recipients,
+            from,
+            sender,
+            reply_to,
+            headers: OnceCell::with_value(headers),
+        }
+    }
+
+    /// Adds an envelope recipient which isn't in the headers, e.g. a Bcc.
+    pub(crate) fn add_recipient(&mut self, mailbox: &str, host: &str) {
+        self.recipients.insert(Recipient {
+            mailbox: mailbox.as_bytes().to_vec(),
+            host: host.as_bytes().to_vec(),
+        });
+    }
+
+    pub(crate) fn recipients(&self) -> impl Iterator<Item = &Recipient> {
+        self.recipients.iter()
+    }
+
+    pub(crate) fn authors(&self) -> impl Iterator<Item = &Recipient> {
+        self.from.iter()
+    }
+
     pub(crate) fn flagged(&self, flag: &str) -> bool {
         self.flags.contains(flag)
     }
@@ -59,13 +166,46 @@ impl Message {
     pub(crate) fn received_by(&self, pattern: &RecipientPattern) -> bool {
         self.recipients.iter().any(|r| pattern.matches(r))
     }
+
+    pub(crate) fn received_by_regex(&self, regex: &bytes::Regex) -> bool {
+        self.recipients.iter().any(|r| {
+            let mut addr = r.mailbox.clone();
+            addr.push(b'@');
+            addr.extend_from_slice(&r.host);
+            regex.is_match(&addr)
+        })
+    }
+
+    pub(crate) fn sent_by(&self, pattern: &RecipientPattern) -> bool {
+        self.from.iter().any(|r| pattern.matches(r))
+    }
+
+    pub(crate) fn sender_is(&self, pattern: &RecipientPattern) -> bool {
+        self.sender.iter().any(|r| pattern.matches(r))
+    }
+
+    pub(crate) fn reply_to(&self, pattern: &RecipientPattern) -> bool {
+        self.reply_to.iter().any(|r| pattern.matches(r))
+    }
+
+    pub(crate) fn header_matches(&self, name: &str, regex: &Regex) -> Result<bool> {
+        let body = self.body()?;
+        Ok(self
+            .headers
+            .get_or_init(|| header::parse(body))
+            .iter()
+            .any(|(n, v)| n.eq_ignore_ascii_case(name) && regex.is_match(v)))
+    }
 }
 
 impl std::convert::TryFrom<&async_imap::types::Fetch> for Message {
     type Error = Error;
 
     fn try_from(message: &async_imap::types::Fetch) -> Result<Self> {
-        let body = message.body().context("message body missing")?.to_vec();
+        let body = OnceCell::new();
+        if let Some(b) = message.body() {
+            _ = body.set(b.to_vec());
+        }
 
         let flags = message
             .flags()
@@ -76,56 +216,91 @@ impl std::convert::TryFrom<&async_imap::types::Fetch> for Message {
             .collect();
 
         let envelope = message.envelope().context("message envelope missing")?;
-        let mut recipients = HashSet::new();
-        for list in [&envelope.to, &envelope.cc, &envelope.bcc]
-            .into_iter()
-            .flatten()
-        {
-            for addr in list {
-                recipients.insert(Recipient {
-                    mailbox: addr
-                        .mailbox
-                        .as_ref()
-                        .and_then(|at| Some(at.to_vec()))
-                        .unwrap_or(vec![]),
-                    host: addr
-                        .host
-                        .as_ref()
-                        .and_then(|at| Some(at.to_vec()))
-                        .unwrap_or(vec![]),
-                });
-            }
-        }
+        let recipients = addresses(&[&envelope.to, &envelope.cc, &envelope.bcc]);
+        let from = addresses(&[&envelope.from]);
+        let sender = addresses(&[&envelope.sender]);
+        let reply_to = addresses(&[&envelope.reply_to]);
         Ok(Message {
             uid: message.uid.context("message uid missing")?,
             body,
+            message_id: envelope.message_id.as_ref().map(|mid| mid.to_vec()),
+            subject: envelope
+                .subject
+                .as_ref()
+                .map(|s| header::decode(&String::from_utf8_lossy(s))),
             flags,
             recipients,
+            from,
+            sender,
+            reply_to,
+            headers: OnceCell::new(),
         })
     }
 }
 
+#[allow(clippy::bind_instead_of_map)]
+fn addresses(lists: &[&Option<Vec<async_imap::imap_proto::Address>>]) -> HashSet<Recipient> {
+    let mut result = HashSet::new();
+    for list in lists.iter().copied().flatten() {
+        for addr in list {
+            result.insert(Recipient {
+                mailbox: addr
+                    .mailbox
+                    .as_ref()
+                    .and_then(|at| Some(at.to_vec()))
+                    .unwrap_or(vec![]),
+                host: addr
+                    .host
+                    .as_ref()
+                    .and_then(|at| Some(at.to_vec()))
+                    .unwrap_or(vec![]),
+            });
+        }
+    }
+    result
+}
+
 #[derive(PartialEq, Eq, Hash)]
 pub(crate) struct Recipient {
     pub(crate) mailbox: Vec<u8>,
     pub(crate) host: Vec<u8>,
 }
 
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}@{}",
+            String::from_utf8_lossy(&self.mailbox),
+            String::from_utf8_lossy(&self.host)
+        )
+    }
+}
+
+pub(crate) struct Mailbox {
+    pub(crate) uid_validity: u32,
+}
+
 pub(crate) enum IdleResult {
     Exists,
     ReIdle,
     ReConnect,
+    Shutdown,
 }
 
 #[async_trait]
 pub(crate) trait EndpointSelector {
-    async fn select(&mut self, folder: &str) -> Result<()>;
+    async fn select(&mut self, folder: &str) -> Result<Mailbox>;
+    /// Selects folder read-only.
+    async fn examine(&mut self, folder: &str) -> Result<Mailbox>;
+    async fn disconnect(&mut self) -> Result<()>;
 }
 
 #[async_trait]
 pub(crate) trait EndpointReader {
-    async fn idle(&mut self) -> Result<IdleResult>;
-    async fn read(&mut self) -> Result<Vec<Message>>;
+    async fn idle(&mut self, shutdown: &mut Shutdown) -> Result<IdleResult>;
+    async fn read(&mut self, min_uid: u32, search: Option<&SearchKey>) -> Result<Vec<Message>>;
+    async fn fetch_body(&mut self, uid: u32) -> Result<Vec<u8>>;
 }
 
 #[async_trait]
@@ -137,17 +312,16 @@ pub(crate) trait EndpointFlagger {
 
 #[async_trait]
 pub(crate) trait SourceEndpoint:
-    EndpointSelector + EndpointReader + EndpointFlagger
+    EndpointSelector + EndpointReader + EndpointFlagger + Send
 {
 }
-impl<T: EndpointSelector + EndpointReader + EndpointFlagger> SourceEndpoint for T {}
+impl<T: EndpointSelector + EndpointReader + EndpointFlagger + Send> SourceEndpoint for T {}
 
 #[async_trait]
 pub(crate) trait EndpointWriter {
     async fn append(&mut self, folder: &str, message: &Message) -> Result<()>;
-    async fn disconnect(&mut self) -> Result<()>;
 }
 
 #[async_trait]
-pub(crate) trait DestinationEndpoint: EndpointSelector + EndpointWriter {}
-impl<T: EndpointSelector + EndpointWriter> DestinationEndpoint for T {}
+pub(crate) trait DestinationEndpoint: EndpointSelector + EndpointWriter + Send {}
+impl<T: EndpointSelector + EndpointWriter + Send> DestinationEndpoint for T {}
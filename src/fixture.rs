@@ -0,0 +1,50 @@
+use anyhow::{Context, Result};
+use std::{fs, path::Path};
+
+/// Reads the raw messages from a file: an mbox if it starts with a "From " line, otherwise a
+/// single message (e.g. an .eml).
+pub(crate) fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Vec<u8>>> {
+    let path = path.as_ref();
+    let data = fs::read(path).with_context(|| format!("can't read mail from {:?}", path))?;
+    if data.starts_with(b"From ") {
+        Ok(split_mbox(&data))
+    } else {
+        Ok(vec![data])
+    }
+}
+
+// Splits on "From " lines, removing one level of ">From " quoting (which covers both mboxo and
+// mboxrd, give or take a ">From " that was in the original message).
+fn split_mbox(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = vec![];
+    let mut current: Option<Vec<u8>> = None;
+
+    for line in data.split_inclusive(|&c| c == b'\n') {
+        if line.starts_with(b"From ") {
+            messages.extend(current.take().map(trim_separator));
+            current = Some(vec![]);
+            continue;
+        }
+        let Some(message) = &mut current else {
+            continue;
+        };
+        let unquoted = line
+            .iter()
+            .position(|&c| c != b'>')
+            .and_then(|gt| (gt > 0 && line[gt..].starts_with(b"From ")).then_some(&line[1..]));
+        message.extend_from_slice(unquoted.unwrap_or(line));
+    }
+    messages.extend(current.map(trim_separator));
+
+    messages
+}
+
+// The blank line before each "From " line belongs to the mbox, not the message.
+fn trim_separator(mut message: Vec<u8>) -> Vec<u8> {
+    if message.ends_with(b"\r\n\r\n") {
+        message.truncate(message.len() - 2);
+    } else if message.ends_with(b"\n\n") {
+        message.truncate(message.len() - 1);
+    }
+    message
+}
@@ -0,0 +1,277 @@
+use encoding_rs::Encoding;
+use std::str;
+
+/// Parses the RFC 5322 header section of a raw message into (name, value) pairs.  Values are
+/// unfolded, trimmed, and have any RFC 2047 encoded-words decoded.  Names are kept as-is; compare
+/// them case-insensitively.
+pub(crate) fn parse(message: &[u8]) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, Vec<u8>)> = vec![];
+
+    for line in message.split(|&c| c == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            break;
+        }
+
+        if line[0] == b' ' || line[0] == b'\t' {
+            // Continuation: unfolding just removes the line break.
+            if let Some((_, value)) = headers.last_mut() {
+                value.extend_from_slice(line);
+            }
+            continue;
+        }
+
+        let Some(colon) = line.iter().position(|&c| c == b':') else {
+            // Not a header line (maybe an mbox "From " line); skip it.
+            continue;
+        };
+        let name = String::from_utf8_lossy(&line[..colon]).trim().to_string();
+        headers.push((name, line[colon + 1..].to_vec()));
+    }
+
+    headers
+        .into_iter()
+        .map(|(name, value)| (name, decode(String::from_utf8_lossy(&value).trim())))
+        .collect()
+}
+
+/// Decodes RFC 2047 encoded-words in an unstructured header value.  Whitespace between two
+/// adjacent encoded-words is dropped.  Encoded-words which fail to decode are left verbatim.
+pub(crate) fn decode(value: &str) -> String {
+    let mut result = String::new();
+    let mut rest = value;
+    let mut pending_ws: Option<&str> = None;
+    let mut last_was_encoded = false;
+
+    while !rest.is_empty() {
+        let ws_len = rest.len() - rest.trim_start().len();
+        if ws_len > 0 {
+            pending_ws = Some(&rest[..ws_len]);
+            rest = &rest[ws_len..];
+            continue;
+        }
+
+        let word_len = rest
+            .find(|c: char| c.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        let (word, tail) = rest.split_at(word_len);
+        rest = tail;
+
+        match decode_word(word) {
+            Some(decoded) => {
+                if let Some(ws) = pending_ws.take() {
+                    if !last_was_encoded {
+                        result.push_str(ws);
+                    }
+                }
+                result.push_str(&decoded);
+                last_was_encoded = true;
+            }
+            None => {
+                if let Some(ws) = pending_ws.take() {
+                    result.push_str(ws);
+                }
+                result.push_str(word);
+                last_was_encoded = false;
+            }
+        }
+    }
+
+    result
+}
+
+fn decode_word(word: &str) -> Option<String> {
+    let inner = word.strip_prefix("=?")?.strip_suffix("?=")?;
+    let mut parts = inner.splitn(3, '?');
+    let charset = parts.next()?;
+    let encoding = parts.next()?;
+    let text = parts.next()?;
+
+    let bytes = match encoding {
+        "B" | "b" => decode_base64(text.as_bytes())?,
+        "Q" | "q" => decode_q(text.as_bytes())?,
+        _ => return None,
+    };
+
+    // RFC 2231 allows a language suffix on the charset: "utf-8*en".
+    let charset = charset.split('*').next()?;
+    match Encoding::for_label(charset.as_bytes()) {
+        Some(encoding) => Some(encoding.decode_without_bom_handling(&bytes).0.into_owned()),
+        None => Some(String::from_utf8_lossy(&bytes).into_owned()),
+    }
+}
+
+fn decode_q(text: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        match text[i] {
+            b'_' => out.push(b' '),
+            // A soft line break, if one survived unfolding.
+            b'=' if text[i + 1..].starts_with(b"\r\n") => i += 2,
+            b'=' if text[i + 1..].starts_with(b"\n") => i += 1,
+            b'=' => {
+                let hex = str::from_utf8(text.get(i + 1..i + 3)?).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 2;
+            }
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    Some(out)
+}
+
+fn decode_base64(text: &[u8]) -> Option<Vec<u8>> {
+    fn sextet(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+            b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let text: Vec<u8> = text.iter().copied().filter(|&c| c != b'=').collect();
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        let mut acc = 0u32;
+        for &c in chunk {
+            acc = (acc << 6) | sextet(c)?;
+        }
+        match chunk.len() {
+            4 => out.extend_from_slice(&[(acc >> 16) as u8, (acc >> 8) as u8, acc as u8]),
+            3 => out.extend_from_slice(&[(acc >> 10) as u8, (acc >> 2) as u8]),
+            2 => out.push((acc >> 4) as u8),
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+/// Extracts the addresses from an address-list header value (To, From, etc.) as (mailbox, host)
+/// pairs, the way an IMAP server builds the envelope.  Display names, comments and group syntax
+/// are dropped.
+pub(crate) fn addresses(value: &str) -> Vec<(String, String)> {
+    let mut result = vec![];
+    let mut outside = String::new();
+    let mut angle: Option<String> = None;
+    let (mut in_angle, mut quoted, mut escaped, mut depth) = (false, false, false, 0);
+
+    let mut flush = |outside: &mut String, angle: &mut Option<String>| {
+        let spec = angle.take().unwrap_or_else(|| outside.clone());
+        outside.clear();
+        let spec = spec.trim();
+        if spec.is_empty() {
+            return;
+        }
+        let (mailbox, host) = spec.rsplit_once('@').unwrap_or((spec, ""));
+        result.push((mailbox.trim().to_string(), host.trim().to_string()));
+    };
+
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+        } else {
+            match c {
+                '\\' if quoted || depth > 0 => {
+                    escaped = true;
+                    continue;
+                }
+                '"' if depth == 0 => {
+                    quoted = !quoted;
+                    continue;
+                }
+                _ if quoted => {}
+                '(' => {
+                    depth += 1;
+                    continue;
+                }
+                ')' if depth > 0 => {
+                    depth -= 1;
+                    continue;
+                }
+                _ if depth > 0 => continue,
+                '<' => {
+                    in_angle = true;
+                    angle = Some(String::new());
+                    continue;
+                }
+                '>' => {
+                    in_angle = false;
+                    continue;
+                }
+                ',' | ';' if !in_angle => {
+                    flush(&mut outside, &mut angle);
+                    continue;
+                }
+                // A group's display name.
+                ':' if !in_angle => {
+                    outside.clear();
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        match &mut angle {
+            Some(spec) if in_angle => spec.push(c),
+            _ => outside.push(c),
+        }
+    }
+    flush(&mut outside, &mut angle);
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn q() {
+        assert_eq!(decode_q(b"a_b=3Dc").unwrap(), b"a b=c");
+        assert_eq!(decode_q(b"ab=\r\ncd=\nef").unwrap(), b"abcdef");
+        assert_eq!(decode_q(b"bad=4"), None);
+        assert_eq!(decode_q(b"bad=zz"), None);
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(decode_base64(b"aGk=").unwrap(), b"hi");
+        assert_eq!(decode_base64(b"aGl5").unwrap(), b"hiy");
+        assert_eq!(decode_base64(b"aA==").unwrap(), b"h");
+        assert_eq!(decode_base64(b"a"), None);
+        assert_eq!(decode_base64(b"a!b="), None);
+    }
+
+    #[test]
+    fn charsets() {
+        assert_eq!(decode("=?utf-8?B?w6lj?="), "éc");
+        assert_eq!(decode("=?ISO-8859-1?Q?caf=E9?="), "café");
+        assert_eq!(decode("=?windows-1252?Q?=80uro_=93q=94?="), "€uro “q”");
+        assert_eq!(decode("=?iso-8859-15?Q?=A4?="), "€");
+        assert_eq!(decode("=?koi8-r?B?8NLJ18XU?="), "Привет");
+        assert_eq!(decode("=?utf-8*en?Q?hi?="), "hi");
+        assert_eq!(decode("=?x-unknown?Q?hi?="), "hi");
+    }
+
+    #[test]
+    fn adjacent_words() {
+        assert_eq!(decode("=?utf-8?Q?a?= =?utf-8?Q?b?="), "ab");
+        assert_eq!(decode("=?utf-8?Q?a?=\t \t=?utf-8?Q?_b?="), "a b");
+        assert_eq!(decode("x =?utf-8?Q?a?= y"), "x a y");
+        assert_eq!(decode("=?utf-8?Q?a?= plain =?utf-8?Q?b?="), "a plain b");
+        assert_eq!(decode("=?utf-8?X?a?= b"), "=?utf-8?X?a?= b");
+    }
+
+    #[test]
+    fn folded() {
+        let headers = parse(b"Subject: =?utf-8?Q?Hello?=\r\n =?utf-8?B?IHdvcmxk?=\r\n\r\nbody");
+        assert_eq!(
+            headers,
+            vec![("Subject".to_string(), "Hello world".to_string())]
+        );
+    }
+}
@@ -1,12 +1,18 @@
 use anyhow::{bail, Context, Result};
+use log::{log, log_enabled, warn, Level};
+use regex::{bytes, Regex};
 
 use super::{Insn, IR};
-use crate::ast::RecipientPattern;
 use crate::endpoint::{DestinationEndpoint, Message, SourceEndpoint};
+use crate::state::State;
+
+const TRACE: &str = "recogedor::trace";
 
 pub(crate) struct Closure<'i> {
     ir: &'i IR,
     folder: String,
+    uid_validity: u32,
+    state: Option<&'i State>,
     slots: Vec<Option<Slot>>,
     src_needs_expunge: bool,
 }
@@ -16,15 +22,28 @@ struct Slot {
 }
 
 impl<'i> Closure<'i> {
-    pub(super) fn new(ir: &'i IR, folder: &str) -> Closure<'i> {
+    pub(super) fn new(
+        ir: &'i IR,
+        folder: &str,
+        uid_validity: u32,
+        state: Option<&'i State>,
+    ) -> Closure<'i> {
         Closure {
             ir,
             folder: folder.to_string(),
+            uid_validity,
+            state,
             slots: ir.dests.iter().map(|_| None).collect(),
             src_needs_expunge: false,
         }
     }
 
+    /// Uses already-connected destinations, one per IR::dests, instead of connecting on demand.
+    pub(crate) fn with_destinations(mut self, dests: Vec<Box<dyn DestinationEndpoint>>) -> Self {
+        self.slots = dests.into_iter().map(|dest| Some(Slot { dest })).collect();
+        self
+    }
+
     async fn slot(&mut self, ix: usize) -> Result<&mut Slot> {
         let slot = self.slots.get_mut(ix).unwrap();
         if let Some(ep) = slot {
@@ -37,6 +56,31 @@ impl<'i> Closure<'i> {
         Ok(slot.as_mut().unwrap())
     }
 
+    // A destination connection may have gone stale since it was opened; reconnect once before
+    // giving up.
+    async fn append(&mut self, ix: usize, mail: &Message) -> Result<()> {
+        let folder = self.folder.to_string();
+        if let Some(slot) = &mut self.slots[ix] {
+            match slot.dest.append(&folder, mail).await {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    warn!(
+                        "[{}] append failed, reconnecting: {:#}",
+                        self.ir.dests[ix].name(),
+                        err
+                    );
+                    self.slots[ix] = None;
+                }
+            }
+        }
+        self.slot(ix)
+            .await
+            .context("connecting destination")?
+            .dest
+            .append(&folder, mail)
+            .await
+    }
+
     pub(crate) async fn process(
         &mut self,
         mail: &Message,
@@ -45,79 +89,199 @@ impl<'i> Closure<'i> {
         let mut stack = Stack::new();
         let mut pc: usize = 0;
 
+        let level = if self.ir.trace {
+            Level::Info
+        } else {
+            Level::Trace
+        };
+        let tracing = log_enabled!(target: TRACE, level);
+        let mut actions = vec![];
+
         while pc < self.ir.insns.len() {
             let insn = &self.ir.insns[pc];
+            let mut next = pc + 1;
+            let mut branch = None;
 
             match insn {
-                Insn::LiteralFlag(fl) => stack.push(Value::Flag(fl.to_string())),
-                Insn::LiteralRecipientPattern(mailbox, plus, host) => {
-                    stack.push(Value::RecipientPattern(RecipientPattern {
-                        mailbox: mailbox.to_owned(),
-                        plus: plus.to_owned(),
-                        host: host.to_owned(),
-                    }))
+                &Insn::LiteralFlag(fl) => stack.push(Value::Flag(fl)),
+                &Insn::LiteralRecipientPattern(rp) => stack.push(Value::RecipientPattern(rp)),
+                Insn::LiteralRecipientRegex(regex) => {
+                    stack.push(Value::RecipientRegex(regex.clone()))
+                }
+                Insn::LiteralHeaderPattern(name, regex) => {
+                    stack.push(Value::HeaderPattern(name.to_owned(), regex.clone()))
                 }
                 &Insn::LiteralDest(dn) => stack.push(Value::Destination(dn)),
 
                 Insn::Flagged => {
-                    let fl = stack.pop_flag()?;
-                    stack.push(Value::Cond(mail.flagged(&fl)));
+                    let fl = &self.ir.flags[stack.pop_flag()?];
+                    stack.push(Value::Cond(mail.flagged(fl)));
                 }
                 Insn::ReceivedBy => {
-                    let p = stack.pop_recipient_pattern()?;
-                    stack.push(Value::Cond(mail.received_by(&p)));
+                    let p = &self.ir.recipient_patterns[stack.pop_recipient_pattern()?];
+                    stack.push(Value::Cond(mail.received_by(p)));
+                }
+                Insn::ReceivedByRegex => {
+                    let re = stack.pop_recipient_regex()?;
+                    stack.push(Value::Cond(mail.received_by_regex(&re)));
+                }
+                Insn::SentBy => {
+                    let p = &self.ir.recipient_patterns[stack.pop_recipient_pattern()?];
+                    stack.push(Value::Cond(mail.sent_by(p)));
+                }
+                Insn::Sender => {
+                    let p = &self.ir.recipient_patterns[stack.pop_recipient_pattern()?];
+                    stack.push(Value::Cond(mail.sender_is(p)));
+                }
+                Insn::ReplyTo => {
+                    let p = &self.ir.recipient_patterns[stack.pop_recipient_pattern()?];
+                    stack.push(Value::Cond(mail.reply_to(p)));
+                }
+                Insn::Header => {
+                    let (name, regex) = stack.pop_header_pattern()?;
+                    mail.load_body(src).await?;
+                    stack.push(Value::Cond(mail.header_matches(&name, &regex)?));
+                }
+                Insn::Seen => {
+                    let state = self.state.context("seen? needs a state_dir")?;
+                    stack.push(Value::Cond(state.seen(
+                        &self.folder,
+                        self.uid_validity,
+                        mail,
+                    )));
                 }
                 Insn::Or => {
                     let c1 = stack.pop_cond()?;
                     let c2 = stack.pop_cond()?;
                     stack.push(Value::Cond(c1 || c2));
                 }
+                Insn::And => {
+                    let c1 = stack.pop_cond()?;
+                    let c2 = stack.pop_cond()?;
+                    stack.push(Value::Cond(c1 && c2));
+                }
+                Insn::Not => {
+                    let c = stack.pop_cond()?;
+                    stack.push(Value::Cond(!c));
+                }
 
                 Insn::Append => {
                     let ix = stack.pop_destination()?;
-                    let folder = self.folder.to_string();
-                    self.slot(ix).await?.dest.append(&folder, mail).await?;
+                    mail.load_body(src).await?;
+                    self.append(ix, mail).await?;
+                    if tracing {
+                        actions.push(format!("(append! {:?})", self.ir.dests[ix].name()));
+                    }
                 }
                 Insn::Flag => {
-                    let fl = stack.pop_flag()?;
-                    src.flag(mail.uid, &fl).await?;
+                    let fl = &self.ir.flags[stack.pop_flag()?];
+                    src.flag(mail.uid, fl).await?;
+                    if tracing {
+                        actions.push(format!("(flag! {:?})", fl));
+                    }
                 }
-                Insn::Halt => break,
+                Insn::Halt => next = self.ir.insns.len(),
                 Insn::Delete => {
                     src.delete(mail.uid).await?;
                     self.src_needs_expunge = true;
+                    if tracing {
+                        actions.push("(delete!)".to_string());
+                    }
                 }
 
-                &Insn::Jump(t) => {
-                    pc = t;
-                    continue;
+                Insn::Remember => {
+                    let state = self.state.context("remember! needs a state_dir")?;
+                    state.remember(&self.folder, self.uid_validity, mail)?;
+                    if tracing {
+                        actions.push("(remember!)".to_string());
+                    }
                 }
+
+                &Insn::Jump(t) => next = t,
                 &Insn::JumpFalse(t) => {
                     let cond = stack.pop_cond()?;
                     if !cond {
-                        pc = t;
-                        continue;
+                        next = t;
                     }
+                    branch = Some(cond);
                 }
             }
 
-            pc += 1;
+            if tracing {
+                let stack = stack
+                    .0
+                    .iter()
+                    .map(|v| self.describe(v))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let branch = match branch {
+                    Some(true) => " (true, falling through)".to_string(),
+                    Some(false) => format!(" (false, jumping to {:02x})", next),
+                    None => String::new(),
+                };
+                log!(
+                    target: TRACE,
+                    level,
+                    "[{}] uid {}: {:02x} {}{} [{}] from {}",
+                    self.folder,
+                    mail.uid,
+                    pc,
+                    insn,
+                    branch,
+                    stack,
+                    self.ir.sources[pc]
+                );
+            }
+            pc = next;
         }
 
+        if tracing {
+            log!(
+                target: TRACE,
+                level,
+                "[{}] uid {}: done, {}",
+                self.folder,
+                mail.uid,
+                if actions.is_empty() {
+                    "no actions".to_string()
+                } else {
+                    actions.join(" ")
+                }
+            );
+        }
         Ok(())
     }
 
-    pub(crate) async fn finish(mut self) -> Result<bool> {
+    // A stack value as the script would have written it.
+    fn describe(&self, value: &Value) -> String {
+        match value {
+            &Value::Flag(fl) => format!("{:?}", self.ir.flags[fl]),
+            &Value::RecipientPattern(rp) => self.ir.recipient_patterns[rp].to_string(),
+            Value::RecipientRegex(regex) => format!("{:?}", regex.as_str()),
+            Value::HeaderPattern(name, regex) => format!("{:?}~{:?}", name, regex.as_str()),
+            &Value::Destination(dn) => format!("{:?}", self.ir.dests[dn].name()),
+            Value::Cond(b) => b.to_string(),
+        }
+    }
+
+    /// Whether anything has been deleted from the source, so it wants expunging.
+    pub(crate) fn needs_expunge(&self) -> bool {
+        self.src_needs_expunge
+    }
+
+    pub(crate) async fn finish(mut self) -> Result<()> {
         for slot in self.slots.iter_mut().flatten() {
             slot.dest.disconnect().await.context("disconnecting")?;
         }
-        Ok(self.src_needs_expunge)
+        Ok(())
     }
 }
 
 pub(crate) enum Value {
-    Flag(String),
-    RecipientPattern(RecipientPattern),
+    Flag(usize),
+    RecipientPattern(usize),
+    RecipientRegex(bytes::Regex),
+    HeaderPattern(String, Regex),
     Destination(usize),
     Cond(bool),
 }
@@ -151,17 +315,31 @@ impl Stack {
         }
     }
 
-    fn pop_flag(&mut self) -> Result<String> {
+    fn pop_flag(&mut self) -> Result<usize> {
         match self.pop()? {
             Value::Flag(fl) => Ok(fl),
             _ => bail!("top of stack wasn't flag"),
         }
     }
 
-    fn pop_recipient_pattern(&mut self) -> Result<RecipientPattern> {
+    fn pop_recipient_pattern(&mut self) -> Result<usize> {
         match self.pop()? {
             Value::RecipientPattern(rp) => Ok(rp),
             _ => bail!("top of stack wasn't recipient pattern"),
         }
     }
+
+    fn pop_recipient_regex(&mut self) -> Result<bytes::Regex> {
+        match self.pop()? {
+            Value::RecipientRegex(re) => Ok(re),
+            _ => bail!("top of stack wasn't recipient regex"),
+        }
+    }
+
+    fn pop_header_pattern(&mut self) -> Result<(String, Regex)> {
+        match self.pop()? {
+            Value::HeaderPattern(name, regex) => Ok((name, regex)),
+            _ => bail!("top of stack wasn't header pattern"),
+        }
+    }
 }
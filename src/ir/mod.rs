@@ -1,24 +1,68 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use regex::{bytes, Regex};
 use std::{collections::HashMap, fmt, str};
 
-use crate::ast::{Cond, Destination, Flag, RecipientPattern, Stmt};
+use crate::ast::{
+    Cond, Destination, Flag, HeaderPattern, RecipientPattern, Span, Spanned, Stmt, SyntaxError,
+};
 use crate::endpoint::Endpoint;
+use crate::state::State;
 
 mod closure;
+mod optimize;
+mod search;
+mod verify;
 use closure::Closure;
+pub(crate) use search::SearchKey;
 
 pub(crate) struct IR {
     insns: Vec<Insn>,
+    // The script form each of insns was compiled from.
+    sources: Vec<Source>,
+    unoptimized: Vec<Insn>,
+    flags: Vec<String>,
+    recipient_patterns: Vec<RecipientPattern>,
     dests: Vec<Endpoint>,
+    search: Option<SearchKey>,
+    trace: bool,
 }
 
 impl IR {
-    pub(super) fn compile(stmts: &[Stmt], dests: HashMap<String, Endpoint>) -> Result<IR> {
+    pub(super) fn compile(stmts: &[Spanned<Stmt>], dests: HashMap<String, Endpoint>) -> Result<IR> {
         IRCompiler::compile(stmts, dests)
     }
 
-    pub(crate) fn closure(&self, folder: &str) -> Closure {
-        Closure::new(self, folder)
+    pub(crate) fn closure<'i>(
+        &'i self,
+        folder: &str,
+        uid_validity: u32,
+        state: Option<&'i State>,
+    ) -> Closure<'i> {
+        Closure::new(self, folder, uid_validity, state)
+    }
+
+    pub(crate) fn dests(&self) -> &[Endpoint] {
+        &self.dests
+    }
+
+    pub(crate) fn search(&self) -> Option<&SearchKey> {
+        self.search.as_ref()
+    }
+
+    /// Drops the prefilter, for a source which isn't to be searched.
+    pub(crate) fn disable_search(&mut self) {
+        self.search = None;
+    }
+
+    /// Traces every message at info level instead of only when recogedor::trace is at trace.
+    pub(crate) fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub(crate) fn uses_state(&self) -> bool {
+        self.insns
+            .iter()
+            .any(|insn| matches!(insn, Insn::Seen | Insn::Remember))
     }
 }
 
@@ -26,27 +70,82 @@ impl fmt::Display for IR {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("\n")?;
 
+        for (ix, fl) in self.flags.iter().enumerate() {
+            writeln!(f, "f{} {:?}", ix, fl)?;
+        }
+        for (ix, p) in self.recipient_patterns.iter().enumerate() {
+            writeln!(f, "rp{} {}", ix, p)?;
+        }
+
+        writeln!(f, "unoptimized:")?;
+        for (ix, insn) in self.unoptimized.iter().enumerate() {
+            writeln!(f, "{:02x} {}", ix, insn)?;
+        }
+        writeln!(f, "optimized:")?;
         for (ix, insn) in self.insns.iter().enumerate() {
             writeln!(f, "{:02x} {}", ix, insn)?;
         }
+        if let Some(search) = &self.search {
+            writeln!(f, "search: {}", search)?;
+        }
         Ok(())
     }
 }
 
+#[derive(Clone)]
+struct Source {
+    span: Span,
+    form: String,
+}
+
+impl Source {
+    // Compound forms are abbreviated; their parts have sources of their own.
+    fn stmt(stmt: &Spanned<Stmt>) -> Source {
+        let form = match &stmt.node {
+            Stmt::If(c, ..) => format!("(if {} …)", c),
+            Stmt::Do(_) => "(do …)".to_string(),
+            s => s.to_string().trim_start().to_string(),
+        };
+        Source {
+            span: stmt.span,
+            form,
+        }
+    }
+
+    fn cond(cond: &Spanned<Cond>) -> Source {
+        Source {
+            span: cond.span,
+            form: cond.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.form, self.span)
+    }
+}
+
 struct IRCompiler {
     i_dests: HashMap<String, Endpoint>,
 
     insns: Vec<Insn>,
+    sources: Vec<Source>,
+    flags: Vec<String>,
+    recipient_patterns: Vec<RecipientPattern>,
     dests: Vec<Endpoint>,
 
     dest_mappings: HashMap<String, usize>,
 }
 
 impl IRCompiler {
-    fn compile(stmts: &[Stmt], dests: HashMap<String, Endpoint>) -> Result<IR> {
+    fn compile(stmts: &[Spanned<Stmt>], dests: HashMap<String, Endpoint>) -> Result<IR> {
         let mut irc = IRCompiler {
             i_dests: dests,
             insns: vec![],
+            sources: vec![],
+            flags: vec![],
+            recipient_patterns: vec![],
             dests: vec![],
             dest_mappings: HashMap::new(),
         };
@@ -55,24 +154,40 @@ impl IRCompiler {
             irc.compile_stmt(stmt)?;
         }
 
-        Ok(IR {
-            insns: irc.insns,
+        let (insns, sources) = optimize::optimize(irc.insns.clone(), irc.sources);
+        let ir = IR {
+            insns,
+            sources,
+            unoptimized: irc.insns,
+            flags: irc.flags,
+            recipient_patterns: irc.recipient_patterns,
             dests: irc.dests,
-        })
+            search: search::prefilter(stmts),
+            trace: false,
+        };
+        verify::verify(&ir, &ir.unoptimized).context("verifying unoptimized IR")?;
+        verify::verify(&ir, &ir.insns).context("verifying optimized IR")?;
+        Ok(ir)
     }
 
-    fn compile_stmt(&mut self, stmt: &Stmt) -> Result<()> {
-        match stmt {
+    fn emit(&mut self, insn: Insn, source: &Source) {
+        self.insns.push(insn);
+        self.sources.push(source.clone());
+    }
+
+    fn compile_stmt(&mut self, stmt: &Spanned<Stmt>) -> Result<()> {
+        let source = Source::stmt(stmt);
+        match &stmt.node {
             Stmt::If(c, t, e) => {
                 self.compile_cond(c)?;
 
                 let else_target = self.insns.len();
-                self.insns.push(Insn::JumpFalse(0));
+                self.emit(Insn::JumpFalse(0), &source);
 
                 self.compile_stmt(t)?;
                 if let Some(e) = e {
                     let done_target = self.insns.len();
-                    self.insns.push(Insn::Jump(0));
+                    self.emit(Insn::Jump(0), &source);
 
                     self.insns[else_target] = Insn::JumpFalse(self.insns.len());
                     self.compile_stmt(e)?;
@@ -88,20 +203,22 @@ impl IRCompiler {
                 }
             }
             Stmt::Append(dn) => {
-                self.compile_dest(dn)?;
-                self.insns.push(Insn::Append);
+                self.compile_dest(dn, &source)
+                    .map_err(|err| SyntaxError::at(stmt.span, err.to_string()))?;
+                self.emit(Insn::Append, &source);
             }
             Stmt::Flag(fl) => {
-                self.compile_flag(fl)?;
-                self.insns.push(Insn::Flag);
+                self.compile_flag(fl, &source)?;
+                self.emit(Insn::Flag, &source);
             }
-            Stmt::Halt => self.insns.push(Insn::Halt),
-            Stmt::Delete => self.insns.push(Insn::Delete),
+            Stmt::Halt => self.emit(Insn::Halt, &source),
+            Stmt::Delete => self.emit(Insn::Delete, &source),
+            Stmt::Remember => self.emit(Insn::Remember, &source),
         }
         Ok(())
     }
 
-    fn compile_dest(&mut self, dn: &Destination) -> Result<()> {
+    fn compile_dest(&mut self, dn: &Destination, source: &Source) -> Result<()> {
         let ix = if let Some(ix) = self.dest_mappings.get(&dn.0) {
             *ix
         } else if let Some(dest) = self.i_dests.remove(&dn.0) {
@@ -112,15 +229,38 @@ impl IRCompiler {
         } else {
             bail!("unknown destination {:?}", dn.0);
         };
-        self.insns.push(Insn::LiteralDest(ix));
+        self.emit(Insn::LiteralDest(ix), source);
         Ok(())
     }
 
-    fn compile_cond(&mut self, cond: &Cond) -> Result<()> {
-        match cond {
+    fn compile_cond(&mut self, cond: &Spanned<Cond>) -> Result<()> {
+        let source = Source::cond(cond);
+        match &cond.node {
             Cond::Or(cx) => {
                 if cx.is_empty() {
-                    bail!("or needs at least one argument");
+                    bail!(SyntaxError::at(
+                        cond.span,
+                        "`or` expects at least 1 argument"
+                    ));
+                }
+
+                let mut ix = cx.len() - 1;
+                self.compile_cond(&cx[ix])?;
+                loop {
+                    if ix == 0 {
+                        break;
+                    }
+                    ix -= 1;
+                    self.compile_cond(&cx[ix])?;
+                    self.emit(Insn::Or, &source);
+                }
+            }
+            Cond::And(cx) => {
+                if cx.is_empty() {
+                    bail!(SyntaxError::at(
+                        cond.span,
+                        "`and` expects at least 1 argument"
+                    ));
                 }
 
                 let mut ix = cx.len() - 1;
@@ -131,83 +271,170 @@ impl IRCompiler {
                     }
                     ix -= 1;
                     self.compile_cond(&cx[ix])?;
-                    self.insns.push(Insn::Or);
+                    self.emit(Insn::And, &source);
                 }
             }
+            Cond::Not(c) => {
+                self.compile_cond(c)?;
+                self.emit(Insn::Not, &source);
+            }
             Cond::Flagged(fl) => {
-                self.compile_flag(fl)?;
-                self.insns.push(Insn::Flagged);
+                self.compile_flag(fl, &source)?;
+                self.emit(Insn::Flagged, &source);
             }
             Cond::ReceivedBy(p) => {
-                self.compile_recipient_pattern(p)?;
-                self.insns.push(Insn::ReceivedBy);
+                self.compile_recipient_pattern(p, &source)?;
+                self.emit(Insn::ReceivedBy, &source);
+            }
+            Cond::ReceivedByRegex(re) => {
+                self.compile_recipient_regex(re, &source)
+                    .map_err(|err| SyntaxError::at(cond.span, format!("{:#}", err)))?;
+                self.emit(Insn::ReceivedByRegex, &source);
+            }
+            Cond::SentBy(p) => {
+                self.compile_recipient_pattern(p, &source)?;
+                self.emit(Insn::SentBy, &source);
+            }
+            Cond::Sender(p) => {
+                self.compile_recipient_pattern(p, &source)?;
+                self.emit(Insn::Sender, &source);
+            }
+            Cond::ReplyTo(p) => {
+                self.compile_recipient_pattern(p, &source)?;
+                self.emit(Insn::ReplyTo, &source);
+            }
+            Cond::Header(hp) => {
+                self.compile_header_pattern(hp, &source)
+                    .map_err(|err| SyntaxError::at(cond.span, format!("{:#}", err)))?;
+                self.emit(Insn::Header, &source);
+            }
+            Cond::Seen => self.emit(Insn::Seen, &source),
+        };
+        Ok(())
+    }
+
+    fn compile_flag(&mut self, fl: &Flag, source: &Source) -> Result<()> {
+        let ix = match self.flags.iter().position(|f| *f == fl.0) {
+            Some(ix) => ix,
+            None => {
+                self.flags.push(fl.0.to_owned());
+                self.flags.len() - 1
+            }
+        };
+        self.emit(Insn::LiteralFlag(ix), source);
+        Ok(())
+    }
+
+    fn compile_recipient_pattern(&mut self, p: &RecipientPattern, source: &Source) -> Result<()> {
+        let ix = match self.recipient_patterns.iter().position(|rp| rp == p) {
+            Some(ix) => ix,
+            None => {
+                self.recipient_patterns.push(p.clone());
+                self.recipient_patterns.len() - 1
             }
         };
+        self.emit(Insn::LiteralRecipientPattern(ix), source);
         Ok(())
     }
 
-    fn compile_flag(&mut self, fl: &Flag) -> Result<()> {
-        self.insns.push(Insn::LiteralFlag(fl.0.to_owned()));
+    fn compile_recipient_regex(&mut self, re: &str, source: &Source) -> Result<()> {
+        let regex = bytes::RegexBuilder::new(re)
+            .case_insensitive(true)
+            .build()
+            .with_context(|| format!("invalid recipient regex {:?}", re))?;
+        self.emit(Insn::LiteralRecipientRegex(regex), source);
         Ok(())
     }
 
-    fn compile_recipient_pattern(&mut self, p: &RecipientPattern) -> Result<()> {
-        self.insns.push(Insn::LiteralRecipientPattern(
-            p.mailbox.to_owned(),
-            p.plus.to_owned(),
-            p.host.to_owned(),
-        ));
+    fn compile_header_pattern(&mut self, hp: &HeaderPattern, source: &Source) -> Result<()> {
+        let regex = Regex::new(&hp.regex)
+            .with_context(|| format!("invalid regex for header {:?}", hp.name))?;
+        self.emit(
+            Insn::LiteralHeaderPattern(hp.name.to_owned(), regex),
+            source,
+        );
         Ok(())
     }
 }
 
+#[derive(Clone)]
 enum Insn {
-    LiteralFlag(String),
-    LiteralRecipientPattern(Option<Vec<u8>>, Option<Vec<u8>>, Option<Vec<u8>>),
+    LiteralFlag(usize),
+    LiteralRecipientPattern(usize),
+    LiteralRecipientRegex(bytes::Regex),
+    LiteralHeaderPattern(String, Regex),
     LiteralDest(usize),
 
     Flagged,
     ReceivedBy,
+    ReceivedByRegex,
+    SentBy,
+    Sender,
+    ReplyTo,
+    Header,
+    Seen,
     Or,
+    And,
+    Not,
 
     Append,
     Flag,
     Halt,
     Delete,
+    Remember,
 
     Jump(usize),
     JumpFalse(usize),
 }
 
+// Regexes don't compare, but their sources do.
+impl PartialEq for Insn {
+    fn eq(&self, other: &Insn) -> bool {
+        match (self, other) {
+            (Insn::LiteralRecipientRegex(a), Insn::LiteralRecipientRegex(b)) => {
+                a.as_str() == b.as_str()
+            }
+            (Insn::LiteralHeaderPattern(na, ra), Insn::LiteralHeaderPattern(nb, rb)) => {
+                na == nb && ra.as_str() == rb.as_str()
+            }
+            (Insn::LiteralFlag(a), Insn::LiteralFlag(b))
+            | (Insn::LiteralRecipientPattern(a), Insn::LiteralRecipientPattern(b))
+            | (Insn::LiteralDest(a), Insn::LiteralDest(b))
+            | (Insn::Jump(a), Insn::Jump(b))
+            | (Insn::JumpFalse(a), Insn::JumpFalse(b)) => a == b,
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b),
+        }
+    }
+}
+
 impl fmt::Display for Insn {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Insn::LiteralFlag(fl) => write!(f, "f{:?}", fl),
-            Insn::LiteralRecipientPattern(mailbox, plus, host) => {
-                f.write_str("rp\"")?;
-                if let Some(mailbox) = mailbox {
-                    f.write_str(str::from_utf8(mailbox).unwrap())?;
-                }
-                if let Some(plus) = plus {
-                    write!(f, "+{}", str::from_utf8(plus).unwrap())?;
-                }
-                f.write_str("@")?;
-                if let Some(host) = host {
-                    f.write_str(str::from_utf8(host).unwrap())?;
-                }
-                f.write_str("\"")?;
-                Ok(())
+            Insn::LiteralFlag(fl) => write!(f, "f{}", fl),
+            Insn::LiteralRecipientPattern(rp) => write!(f, "rp{}", rp),
+            Insn::LiteralRecipientRegex(regex) => write!(f, "rr{:?}", regex.as_str()),
+            Insn::LiteralHeaderPattern(name, regex) => {
+                write!(f, "hp{:?}~{:?}", name, regex.as_str())
             }
             Insn::LiteralDest(dn) => write!(f, "d{}", dn),
 
             Insn::Flagged => f.write_str("flagged?"),
             Insn::ReceivedBy => f.write_str("received-by?"),
+            Insn::ReceivedByRegex => f.write_str("received-by-regex?"),
+            Insn::SentBy => f.write_str("sent-by?"),
+            Insn::Sender => f.write_str("sender?"),
+            Insn::ReplyTo => f.write_str("reply-to?"),
+            Insn::Header => f.write_str("header?"),
+            Insn::Seen => f.write_str("seen?"),
             Insn::Or => f.write_str("or"),
+            Insn::And => f.write_str("and"),
+            Insn::Not => f.write_str("not"),
 
             Insn::Append => f.write_str("append!"),
             Insn::Flag => f.write_str("flag!"),
             Insn::Halt => f.write_str("halt!"),
             Insn::Delete => f.write_str("delete!"),
+            Insn::Remember => f.write_str("remember!"),
 
             Insn::Jump(d) => write!(f, "j {:02x}", d),
             Insn::JumpFalse(d) => write!(f, "jfalse {:02x}", d),
@@ -215,23 +442,26 @@ impl fmt::Display for Insn {
     }
 }
 
-// 00 f"Recogido"
+// The README's example script, optimized:
+//
+// f0 "Recogido"
+// rp0 "s.fox@foxden.net"
+// rp1 "fox@den.com"
+// 00 f0
 // 01 flagged?
 // 02 jfalse 04
 // 03 halt!
-// 04 rp"fox@den.com"
+// 04 rp0
 // 05 received-by?
-// 06 rp"fox@foxden.net"
+// 06 rp1
 // 07 received-by?
 // 08 or
-// 09 rp"fx@"
-// 0a received-by?
-// 0b or
-// 0c jfalse 10
-// 0d d0
-// 0e append!
-// 0f j 12
-// 10 d1
-// 11 append!
-// 12 f"Recogido"
-// 13 flag!
+// 09 jfalse 0f
+// 0a d0
+// 0b append!
+// 0c f0
+// 0d flag!
+// 0e halt!
+// 0f d1
+// 10 append!
+// 11 delete!
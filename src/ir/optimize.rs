@@ -0,0 +1,552 @@
+use std::ops::Range;
+
+use super::{Insn, Source};
+
+// Each pass makes at most one kind of change and leaves cleaning up after itself to the others;
+// they're run in turn until none of them finds anything left to do.  sources runs parallel to
+// insns; rewritten instructions keep the source of the one they replace.
+pub(super) fn optimize(mut insns: Vec<Insn>, mut sources: Vec<Source>) -> (Vec<Insn>, Vec<Source>) {
+    loop {
+        let before = insns.clone();
+        fold_duplicate_operands(&mut insns, &mut sources);
+        extract_common_tail(&mut insns);
+        thread_jumps(&mut insns);
+        remove_dead_code(&mut insns, &mut sources);
+        if insns == before {
+            return (insns, sources);
+        }
+    }
+}
+
+// (or a b a) => (or a b), and likewise for and.  Conditions have no side effects, so evaluating
+// one twice is the same as evaluating it once.
+fn fold_duplicate_operands(insns: &mut Vec<Insn>, sources: &mut Vec<Source>) {
+    'again: loop {
+        let targets = jump_targets(insns);
+        for ix in 0..insns.len() {
+            if !matches!(insns[ix], Insn::Or | Insn::And) {
+                continue;
+            }
+            let Some(b) = operand(insns, ix) else {
+                continue;
+            };
+            let Some(a) = operand(insns, b.start) else {
+                continue;
+            };
+            if targets.iter().any(|&t| t > a.start && t <= ix) {
+                continue;
+            }
+            if leaves(insns, a, &insns[ix])
+                .into_iter()
+                .any(|leaf| insns[leaf] == insns[b.clone()])
+            {
+                let mut dead = vec![false; insns.len()];
+                for d in &mut dead[b.start..=ix] {
+                    *d = true;
+                }
+                compact(insns, sources, &dead);
+                continue 'again;
+            }
+        }
+        return;
+    }
+}
+
+// The range of the cond expression which ends just before `end`, if it's a pure one.
+fn operand(insns: &[Insn], end: usize) -> Option<Range<usize>> {
+    let mut needed = 1;
+    let mut ix = end;
+    while ix > 0 {
+        ix -= 1;
+        let (pops, pushes) = expression_effect(&insns[ix])?;
+        needed = needed + pops - pushes;
+        if needed == 0 {
+            return Some(ix..end);
+        }
+    }
+    None
+}
+
+// The operands of a chain of `op`s, e.g. the ranges of a, b and c in "c b or a or".
+fn leaves(insns: &[Insn], range: Range<usize>, op: &Insn) -> Vec<Range<usize>> {
+    if range.len() > 1 && insns[range.end - 1] == *op {
+        let inner = range.end - 1;
+        if let Some(b) = operand(insns, inner) {
+            if let Some(a) = operand(insns, b.start) {
+                if a.start == range.start {
+                    let mut result = leaves(insns, a, op);
+                    result.extend(leaves(insns, b, op));
+                    return result;
+                }
+            }
+        }
+    }
+    vec![range]
+}
+
+// (pops, pushes) for instructions which may appear in a cond expression.
+fn expression_effect(insn: &Insn) -> Option<(usize, usize)> {
+    match insn {
+        Insn::LiteralFlag(_)
+        | Insn::LiteralRecipientPattern(_)
+        | Insn::LiteralRecipientRegex(_)
+        | Insn::LiteralHeaderPattern(..)
+        | Insn::Seen => Some((0, 1)),
+        Insn::Flagged
+        | Insn::ReceivedBy
+        | Insn::ReceivedByRegex
+        | Insn::SentBy
+        | Insn::Sender
+        | Insn::ReplyTo
+        | Insn::Header
+        | Insn::Not => Some((1, 1)),
+        Insn::Or | Insn::And => Some((2, 1)),
+        Insn::LiteralDest(_)
+        | Insn::Append
+        | Insn::Flag
+        | Insn::Halt
+        | Insn::Delete
+        | Insn::Remember
+        | Insn::Jump(_)
+        | Insn::JumpFalse(_) => None,
+    }
+}
+
+// If the code just before a forward jump is identical to the code just before its target, the
+// jump can be taken earlier:
+//
+//   jfalse E; ...; X; Y; j D; E: ...; X; Y; D:  =>  jfalse E; ...; j E'; E: ...; E': X; Y; D:
+//
+// This is the README's `if x { flag y } else { flag z }`.  The then-branch's copy becomes
+// unreachable and is removed by remove_dead_code.
+fn extract_common_tail(insns: &mut [Insn]) {
+    for j in 0..insns.len() {
+        let Insn::Jump(d) = insns[j] else {
+            continue;
+        };
+        if d <= j + 1 || d > insns.len() {
+            continue;
+        }
+
+        let mut k = 0;
+        while k < j && d - 1 - k > j && insns[j - 1 - k] == insns[d - 1 - k] {
+            k += 1;
+        }
+        if k == 0 {
+            continue;
+        }
+
+        // Anything jumping into the middle of the then-branch's copy goes to the same place in
+        // the other copy instead.
+        let start = j - k;
+        for insn in insns.iter_mut() {
+            if let Insn::Jump(t) | Insn::JumpFalse(t) = insn {
+                if *t > start && *t <= j {
+                    *t = *t + d - j;
+                }
+            }
+        }
+        insns[start] = Insn::Jump(d - k);
+        return;
+    }
+}
+
+// Jumps to jumps go straight to the final target; jumps to a halt, or off the end, just halt.
+fn thread_jumps(insns: &mut [Insn]) {
+    for ix in 0..insns.len() {
+        let (Insn::Jump(t) | Insn::JumpFalse(t)) = insns[ix] else {
+            continue;
+        };
+
+        let mut target = t;
+        let mut hops = 0;
+        while let Some(&Insn::Jump(next)) = insns.get(target) {
+            if hops > insns.len() {
+                // A loop.  Not something the compiler produces, but leave it be.
+                target = t;
+                break;
+            }
+            target = next;
+            hops += 1;
+        }
+
+        let halts = target >= insns.len() || matches!(insns[target], Insn::Halt);
+        insns[ix] = match insns[ix] {
+            Insn::Jump(_) if halts => Insn::Halt,
+            Insn::Jump(_) => Insn::Jump(target),
+            _ => Insn::JumpFalse(target),
+        };
+    }
+}
+
+// Removes unreachable code (e.g. after a halt), jumps to the next instruction, and a final halt.
+fn remove_dead_code(insns: &mut Vec<Insn>, sources: &mut Vec<Source>) {
+    let mut reachable = vec![false; insns.len()];
+    let mut work = vec![0];
+    while let Some(ix) = work.pop() {
+        if ix >= insns.len() || reachable[ix] {
+            continue;
+        }
+        reachable[ix] = true;
+        match insns[ix] {
+            Insn::Jump(t) => work.push(t),
+            Insn::JumpFalse(t) => work.extend([ix + 1, t]),
+            Insn::Halt => {}
+            _ => work.push(ix + 1),
+        }
+    }
+
+    let last = insns.len().wrapping_sub(1);
+    let dead: Vec<bool> = insns
+        .iter()
+        .enumerate()
+        .map(|(ix, insn)| {
+            !reachable[ix]
+                || matches!(insn, &Insn::Jump(t) if t == ix + 1)
+                || (ix == last && matches!(insn, Insn::Halt))
+        })
+        .collect();
+    compact(insns, sources, &dead);
+}
+
+// Drops the dead instructions.  Jumps to a dead instruction go to the next live one.
+fn compact(insns: &mut Vec<Insn>, sources: &mut Vec<Source>, dead: &[bool]) {
+    let mut new_ix = Vec::with_capacity(insns.len() + 1);
+    let mut live = 0;
+    for &d in dead {
+        new_ix.push(live);
+        if !d {
+            live += 1;
+        }
+    }
+    new_ix.push(live);
+
+    let old = std::mem::take(insns);
+    for (insn, &d) in old.into_iter().zip(dead) {
+        if d {
+            continue;
+        }
+        insns.push(match insn {
+            Insn::Jump(t) => Insn::Jump(new_ix[t]),
+            Insn::JumpFalse(t) => Insn::JumpFalse(new_ix[t]),
+            insn => insn,
+        });
+    }
+
+    let old = std::mem::take(sources);
+    sources.extend(
+        old.into_iter()
+            .zip(dead)
+            .filter_map(|(source, &d)| (!d).then_some(source)),
+    );
+}
+
+fn jump_targets(insns: &[Insn]) -> Vec<usize> {
+    insns
+        .iter()
+        .filter_map(|insn| match insn {
+            &Insn::Jump(t) | &Insn::JumpFalse(t) => Some(t),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::compile_for_test;
+    use std::collections::HashMap;
+
+    type Pass = fn(&mut Vec<Insn>, &mut Vec<Source>);
+
+    const PASSES: [(&str, Pass); 4] = [
+        ("fold_duplicate_operands", fold_duplicate_operands),
+        ("extract_common_tail", |insns, _| extract_common_tail(insns)),
+        ("thread_jumps", |insns, _| thread_jumps(insns)),
+        ("remove_dead_code", remove_dead_code),
+    ];
+
+    const README: &str = r#"
+        (if (flagged "Recogido") (halt!))
+        (if
+          (or
+            (received-by "fox@den.com")
+            (received-by "s.fox@foxden.net"))
+          (do
+            (append! "d0")
+            (flag! "Recogido"))
+          (do
+            (append! "d1")
+            (delete!)))
+    "#;
+
+    enum Value {
+        Literal(String),
+        Cond(bool),
+    }
+
+    // Runs insns with each condition's outcome looked up in env by its literal and predicate,
+    // e.g. "f0 flagged?", returning the actions taken.
+    fn run(insns: &[Insn], env: &HashMap<String, bool>) -> Vec<String> {
+        let mut stack = vec![];
+        let cond = |stack: &mut Vec<Value>| match stack.pop() {
+            Some(Value::Cond(b)) => b,
+            _ => panic!("expected a cond"),
+        };
+        let literal = |stack: &mut Vec<Value>| match stack.pop() {
+            Some(Value::Literal(l)) => l,
+            _ => panic!("expected a literal"),
+        };
+        let mut actions = vec![];
+        let mut pc = 0;
+        let mut steps = 0;
+        while pc < insns.len() {
+            steps += 1;
+            assert!(steps < 10_000, "doesn't terminate");
+            let mut next = pc + 1;
+            match &insns[pc] {
+                Insn::LiteralFlag(_)
+                | Insn::LiteralRecipientPattern(_)
+                | Insn::LiteralRecipientRegex(_)
+                | Insn::LiteralHeaderPattern(..)
+                | Insn::LiteralDest(_) => stack.push(Value::Literal(insns[pc].to_string())),
+                Insn::Seen => stack.push(Value::Cond(env.get("seen?") == Some(&true))),
+                insn @ (Insn::Flagged
+                | Insn::ReceivedBy
+                | Insn::ReceivedByRegex
+                | Insn::SentBy
+                | Insn::Sender
+                | Insn::ReplyTo
+                | Insn::Header) => {
+                    let key = format!("{} {}", literal(&mut stack), insn);
+                    stack.push(Value::Cond(env.get(&key) == Some(&true)));
+                }
+                Insn::Or => {
+                    let (a, b) = (cond(&mut stack), cond(&mut stack));
+                    stack.push(Value::Cond(a || b));
+                }
+                Insn::And => {
+                    let (a, b) = (cond(&mut stack), cond(&mut stack));
+                    stack.push(Value::Cond(a && b));
+                }
+                Insn::Not => {
+                    let a = cond(&mut stack);
+                    stack.push(Value::Cond(!a));
+                }
+                insn @ (Insn::Append | Insn::Flag) => {
+                    actions.push(format!("{} {}", insn, literal(&mut stack)))
+                }
+                Insn::Delete | Insn::Remember => actions.push(insns[pc].to_string()),
+                Insn::Halt => next = insns.len(),
+                &Insn::Jump(t) => next = t,
+                &Insn::JumpFalse(t) => {
+                    if !cond(&mut stack) {
+                        next = t;
+                    }
+                }
+            }
+            pc = next;
+        }
+        assert!(stack.is_empty(), "stack left with {} values", stack.len());
+        actions
+    }
+
+    // Every assignment of outcomes to the script's conditions, or a sample if there are many.
+    fn envs(insns: &[Insn]) -> Vec<HashMap<String, bool>> {
+        let mut keys: Vec<String> = insns
+            .windows(2)
+            .filter(|w| expression_effect(&w[1]) == Some((1, 1)) && w[1] != Insn::Not)
+            .map(|w| format!("{} {}", w[0], w[1]))
+            .collect();
+        keys.push("seen?".to_string());
+        keys.sort();
+        keys.dedup();
+
+        let mut rng = Rng(keys.len() as u64 + 1);
+        let count = 1u64 << keys.len().min(10);
+        (0..count)
+            .map(|n| {
+                let bits = if keys.len() <= 10 { n } else { rng.next() };
+                keys.iter()
+                    .enumerate()
+                    .map(|(ix, k)| (k.clone(), bits >> ix & 1 == 1))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn listing(insns: &[Insn]) -> String {
+        insns
+            .iter()
+            .enumerate()
+            .map(|(ix, insn)| format!("{:02x} {}\n", ix, insn))
+            .collect()
+    }
+
+    fn assert_same(script: &str, what: &str, before: &[Insn], after: &[Insn]) {
+        for env in envs(before) {
+            assert_eq!(
+                run(before, &env),
+                run(after, &env),
+                "{} changed behaviour of {}\nwith {:?}\nfrom\n{}to\n{}",
+                what,
+                script,
+                env,
+                listing(before),
+                listing(after)
+            );
+        }
+    }
+
+    // Checks each pass on its own from the unoptimized code, then every step of the real
+    // sequence, and that the sequence ends where optimize does.
+    fn check(script: &str) {
+        let ir = compile_for_test(script, &["d0", "d1"]).unwrap();
+        // The passes only move sources around, so any will do.
+        let source = compile_for_test(README, &["d0", "d1"]).unwrap().sources[0].clone();
+        let unoptimized = &ir.unoptimized;
+
+        for (name, pass) in PASSES {
+            let mut insns = unoptimized.clone();
+            let mut sources = vec![source.clone(); insns.len()];
+            for _ in 0..insns.len() + 1 {
+                let before = insns.clone();
+                pass(&mut insns, &mut sources);
+                assert_eq!(insns.len(), sources.len(), "{} lost track of sources", name);
+                if insns == before {
+                    break;
+                }
+            }
+            assert_same(script, name, unoptimized, &insns);
+        }
+
+        let mut insns = unoptimized.clone();
+        let mut sources = vec![source.clone(); insns.len()];
+        loop {
+            let round = insns.clone();
+            for (name, pass) in PASSES {
+                let before = insns.clone();
+                pass(&mut insns, &mut sources);
+                assert_same(script, name, &before, &insns);
+            }
+            if insns == round {
+                break;
+            }
+        }
+        assert!(insns == ir.insns, "optimize differs for {}", script);
+    }
+
+    // xorshift64, so the random scripts are the same every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    fn random_cond(rng: &mut Rng, depth: u32) -> String {
+        match rng.below(if depth > 2 { 4 } else { 7 }) {
+            0 => format!("(flagged \"{}\")", ["a", "b", "c"][rng.below(3) as usize]),
+            1 => format!("(received-by \"{}\")", ["x@", "y@z"][rng.below(2) as usize]),
+            2 => format!(
+                "(header \"Subject\" \"{}\")",
+                ["hi", "yo"][rng.below(2) as usize]
+            ),
+            3 => "(seen?)".to_string(),
+            4 => format!("(not {})", random_cond(rng, depth + 1)),
+            n => {
+                let operands: Vec<_> = (0..2 + rng.below(2))
+                    .map(|_| random_cond(rng, depth + 1))
+                    .collect();
+                let op = if n == 5 { "and" } else { "or" };
+                format!("({} {})", op, operands.join(" "))
+            }
+        }
+    }
+
+    fn random_stmt(rng: &mut Rng, depth: u32) -> String {
+        match rng.below(if depth > 3 { 5 } else { 8 }) {
+            0 => format!("(append! \"{}\")", ["d0", "d1"][rng.below(2) as usize]),
+            1 => format!("(flag! \"{}\")", ["a", "b"][rng.below(2) as usize]),
+            2 => "(delete!)".to_string(),
+            3 => "(halt!)".to_string(),
+            4 => "(remember!)".to_string(),
+            5 => format!(
+                "(if {} {})",
+                random_cond(rng, 0),
+                random_stmt(rng, depth + 1)
+            ),
+            6 => format!(
+                "(if {} {} {})",
+                random_cond(rng, 0),
+                random_stmt(rng, depth + 1),
+                random_stmt(rng, depth + 1)
+            ),
+            _ => {
+                let body: Vec<_> = (0..1 + rng.below(3))
+                    .map(|_| random_stmt(rng, depth + 1))
+                    .collect();
+                format!("(do {})", body.join(" "))
+            }
+        }
+    }
+
+    #[test]
+    fn readme() {
+        check(README);
+    }
+
+    #[test]
+    fn common_tail_jumped_into() {
+        // A jump into the then-branch's copy of the tail used to underflow when redirected.
+        check(
+            r#"(if (flagged "c") (do (do (flag! "a")) (halt!))) (do (append! "d0") (if (received-by "x@") (if (header "Subject" "yo") (if (not (or (header "Subject" "hi") (flagged "a"))) (halt!) (delete!)) (do (append! "d0") (halt!))) (delete!)) (delete!)) (halt!) (append! "d0")"#,
+        );
+    }
+
+    #[test]
+    fn folds_duplicates() {
+        let script = r#"(if (or (flagged "a") (flagged "b") (flagged "a")) (delete!))"#;
+        check(script);
+        let ir = compile_for_test(script, &[]).unwrap();
+        assert_eq!(
+            ir.insns.iter().filter(|i| **i == Insn::Flagged).count(),
+            2,
+            "{}",
+            listing(&ir.insns)
+        );
+    }
+
+    #[test]
+    fn extracts_common_tails() {
+        let script =
+            r#"(if (flagged "a") (do (append! "d0") (flag! "x")) (do (append! "d1") (flag! "x")))"#;
+        check(script);
+        let ir = compile_for_test(script, &["d0", "d1"]).unwrap();
+        assert_eq!(
+            ir.insns.iter().filter(|i| **i == Insn::Flag).count(),
+            1,
+            "{}",
+            listing(&ir.insns)
+        );
+    }
+
+    #[test]
+    fn random_scripts() {
+        let mut rng = Rng(0x5eed);
+        for _ in 0..500 {
+            let script: Vec<_> = (0..1 + rng.below(4))
+                .map(|_| random_stmt(&mut rng, 0))
+                .collect();
+            check(&script.join(" "));
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::{fmt, str};
+
+use crate::ast::{Cond, RecipientPattern, Spanned, Stmt};
+
+// A server-side prefilter is an IMAP SEARCH key which matches (at least) every mail item the script
+// might do something to.  Mail the key doesn't match is never fetched.  The analysis is
+// conservative: anything it doesn't understand becomes All.
+//
+// Two kinds of translation are used.  `exact` produces a key matching precisely when the condition
+// is true, and is only possible for flags.  `superset` produces a key matching whenever the
+// condition might be true; received-by and sent-by use substring address searches, which match
+// more than the recipient pattern does, but never less, on servers which do match substrings.
+// Not all do, which is why the prefilter is opt-in.
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum SearchKey {
+    All,
+    Nothing,
+    Flag(&'static str),
+    Keyword(String),
+    Address(&'static str, String),
+    Not(Box<SearchKey>),
+    And(Vec<SearchKey>),
+    Or(Vec<SearchKey>),
+}
+
+/// Returns a SEARCH key for the mail items the script might act on, or None if that's everything.
+pub(super) fn prefilter(stmts: &[Spanned<Stmt>]) -> Option<SearchKey> {
+    match acts_seq(&flatten(stmts)) {
+        SearchKey::All => None,
+        key => Some(key),
+    }
+}
+
+fn flatten(stmts: &[Spanned<Stmt>]) -> Vec<&Stmt> {
+    let mut result = vec![];
+    for stmt in stmts {
+        match &stmt.node {
+            Stmt::Do(sx) => result.extend(flatten(sx)),
+            s => result.push(s),
+        }
+    }
+    result
+}
+
+// Leading (if C (halt!)) guards exclude everything C matches from the rest of the sequence.
+fn acts_seq(stmts: &[&Stmt]) -> SearchKey {
+    let Some((first, rest)) = stmts.split_first() else {
+        return SearchKey::Nothing;
+    };
+    match first {
+        Stmt::Halt => SearchKey::Nothing,
+        Stmt::If(c, t, None) if matches!(t.node, Stmt::Halt) => {
+            and(vec![negated(c), acts_seq(rest)])
+        }
+        s => or(vec![acts(s), acts_seq(rest)]),
+    }
+}
+
+fn acts(stmt: &Stmt) -> SearchKey {
+    match stmt {
+        Stmt::If(c, t, e) => {
+            let mut branches = vec![and(vec![superset(c), acts(t)])];
+            if let Some(e) = e {
+                branches.push(and(vec![negated(c), acts(e)]));
+            }
+            or(branches)
+        }
+        Stmt::Do(sx) => or(sx.iter().map(|s| acts(s)).collect()),
+        Stmt::Halt => SearchKey::Nothing,
+        Stmt::Append(_) | Stmt::Flag(_) | Stmt::Delete | Stmt::Remember => SearchKey::All,
+    }
+}
+
+fn exact(cond: &Cond) -> Option<SearchKey> {
+    match cond {
+        Cond::Flagged(fl) => flag(&fl.0),
+        Cond::Not(c) => Some(not(exact(c)?)),
+        Cond::And(cx) => Some(and(cx.iter().map(|c| exact(c)).collect::<Option<_>>()?)),
+        Cond::Or(cx) => Some(or(cx.iter().map(|c| exact(c)).collect::<Option<_>>()?)),
+        _ => None,
+    }
+}
+
+fn superset(cond: &Cond) -> SearchKey {
+    match cond {
+        Cond::Flagged(_) | Cond::Not(_) => exact(cond).unwrap_or(SearchKey::All),
+        Cond::And(cx) => and(cx.iter().map(|c| superset(c)).collect()),
+        Cond::Or(cx) => or(cx.iter().map(|c| superset(c)).collect()),
+        Cond::ReceivedBy(p) => match address_substring(p) {
+            Some(s) => or(vec![
+                SearchKey::Address("TO", s.clone()),
+                SearchKey::Address("CC", s.clone()),
+                SearchKey::Address("BCC", s),
+            ]),
+            None => SearchKey::All,
+        },
+        Cond::SentBy(p) => match address_substring(p) {
+            Some(s) => SearchKey::Address("FROM", s),
+            None => SearchKey::All,
+        },
+        // The envelope falls back to From for these when the header's absent, so a header search
+        // could miss.
+        Cond::Sender(_) | Cond::ReplyTo(_) => SearchKey::All,
+        Cond::ReceivedByRegex(_) | Cond::Header(_) | Cond::Seen => SearchKey::All,
+    }
+}
+
+fn negated(cond: &Cond) -> SearchKey {
+    exact(cond).map(not).unwrap_or(SearchKey::All)
+}
+
+fn flag(fl: &str) -> Option<SearchKey> {
+    let system = match fl {
+        r"\Seen" => "SEEN",
+        r"\Answered" => "ANSWERED",
+        r"\Flagged" => "FLAGGED",
+        r"\Deleted" => "DELETED",
+        r"\Draft" => "DRAFT",
+        r"\Recent" => "RECENT",
+        _ => {
+            let atom = !fl.is_empty()
+                && fl
+                    .bytes()
+                    .all(|c| c.is_ascii_graphic() && !b"(){%*\"\\]".contains(&c));
+            return atom.then(|| SearchKey::Keyword(fl.to_string()));
+        }
+    };
+    Some(SearchKey::Flag(system))
+}
+
+// A literal part of the pattern that must appear in the address of any recipient it matches.
+fn address_substring(p: &RecipientPattern) -> Option<String> {
+    let literal = |part: &Option<Vec<u8>>| {
+        part.as_ref()
+            .filter(|b| {
+                !b.is_empty()
+                    && b.iter()
+                        .all(|&c| c.is_ascii_graphic() && c != b'*' && c != b'?')
+            })
+            .map(|b| str::from_utf8(b).unwrap().to_string())
+    };
+    if let Some(host) = literal(&p.host) {
+        Some(format!("@{}", host))
+    } else if let Some(mailbox) = literal(&p.mailbox) {
+        Some(mailbox)
+    } else {
+        literal(&p.plus).map(|plus| format!("+{}", plus))
+    }
+}
+
+fn not(key: SearchKey) -> SearchKey {
+    match key {
+        SearchKey::All => SearchKey::Nothing,
+        SearchKey::Nothing => SearchKey::All,
+        SearchKey::Not(k) => *k,
+        k => SearchKey::Not(Box::new(k)),
+    }
+}
+
+fn and(keys: Vec<SearchKey>) -> SearchKey {
+    let mut result = vec![];
+    for key in keys {
+        match key {
+            SearchKey::All => {}
+            SearchKey::Nothing => return SearchKey::Nothing,
+            SearchKey::And(kx) => result.extend(kx),
+            k => result.push(k),
+        }
+    }
+    // x AND NOT x matches nothing.
+    if simplify(&mut result) {
+        return SearchKey::Nothing;
+    }
+    match result.len() {
+        0 => SearchKey::All,
+        1 => result.pop().unwrap(),
+        _ => SearchKey::And(result),
+    }
+}
+
+fn or(keys: Vec<SearchKey>) -> SearchKey {
+    let mut result = vec![];
+    for key in keys {
+        match key {
+            SearchKey::All => return SearchKey::All,
+            SearchKey::Nothing => {}
+            SearchKey::Or(kx) => result.extend(kx),
+            k => result.push(k),
+        }
+    }
+    // x OR NOT x matches everything.
+    if simplify(&mut result) {
+        return SearchKey::All;
+    }
+    match result.len() {
+        0 => SearchKey::Nothing,
+        1 => result.pop().unwrap(),
+        _ => SearchKey::Or(result),
+    }
+}
+
+// Drops repeated operands, and returns whether any operand is another's negation.
+fn simplify(keys: &mut Vec<SearchKey>) -> bool {
+    let mut unique: Vec<SearchKey> = vec![];
+    for key in keys.drain(..) {
+        if !unique.contains(&key) {
+            unique.push(key);
+        }
+    }
+    *keys = unique;
+    keys.iter().any(|k| match k {
+        SearchKey::Not(inner) => keys.contains(inner),
+        _ => false,
+    })
+}
+
+impl fmt::Display for SearchKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchKey::All => f.write_str("ALL"),
+            SearchKey::Nothing => f.write_str("NOT ALL"),
+            SearchKey::Flag(fl) => f.write_str(fl),
+            SearchKey::Keyword(kw) => write!(f, "KEYWORD {}", kw),
+            SearchKey::Address(field, s) => {
+                write!(
+                    f,
+                    "{} \"{}\"",
+                    field,
+                    s.replace('\\', r"\\").replace('"', "\\\"")
+                )
+            }
+            SearchKey::Not(k) => write!(f, "NOT {}", k),
+            SearchKey::And(kx) => {
+                f.write_str("(")?;
+                for (ix, k) in kx.iter().enumerate() {
+                    if ix > 0 {
+                        f.write_str(" ")?;
+                    }
+                    k.fmt(f)?;
+                }
+                f.write_str(")")
+            }
+            SearchKey::Or(kx) => {
+                // OR is binary: OR a OR b c.
+                for k in &kx[..kx.len() - 1] {
+                    write!(f, "OR {} ", k)?;
+                }
+                kx[kx.len() - 1].fmt(f)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::compile_for_test;
+
+    fn prefilter(script: &str) -> Option<String> {
+        let ir = compile_for_test(script, &["d"]).unwrap();
+        ir.search().map(|k| k.to_string())
+    }
+
+    fn kw(k: &str) -> SearchKey {
+        SearchKey::Keyword(k.to_string())
+    }
+
+    #[test]
+    fn complements() {
+        assert!(or(vec![kw("a"), not(kw("a"))]) == SearchKey::All);
+        assert!(and(vec![kw("a"), not(kw("a"))]) == SearchKey::Nothing);
+        assert!(or(vec![kw("a"), kw("b"), kw("a")]) == or(vec![kw("a"), kw("b")]));
+        assert_eq!(
+            or(vec![
+                and(vec![kw("a"), or(vec![kw("b"), not(kw("b"))])]),
+                not(kw("a"))
+            ]),
+            SearchKey::All
+        );
+    }
+
+    #[test]
+    fn rendering() {
+        assert_eq!(
+            or(vec![kw("a"), and(vec![kw("b"), not(kw("c"))]), kw("d")]).to_string(),
+            "OR KEYWORD a OR (KEYWORD b NOT KEYWORD c) KEYWORD d"
+        );
+        assert_eq!(
+            SearchKey::Address("TO", "a\"b\\c".to_string()).to_string(),
+            r#"TO "a\"b\\c""#
+        );
+    }
+
+    #[test]
+    fn scripts() {
+        assert_eq!(prefilter(r#"(append! "d")"#), None);
+        assert_eq!(
+            prefilter(r#"(if (flagged "a") (halt!)) (append! "d")"#).as_deref(),
+            Some("NOT KEYWORD a")
+        );
+        assert_eq!(
+            prefilter(r#"(if (received-by "fox@den.com") (append! "d"))"#).as_deref(),
+            Some(r#"OR TO "@den.com" OR CC "@den.com" BCC "@den.com""#)
+        );
+        assert_eq!(
+            prefilter(r#"(if (flagged "\\Seen") (halt!)) (if (sent-by "*@x.org") (delete!))"#)
+                .as_deref(),
+            Some(r#"(NOT SEEN FROM "@x.org")"#)
+        );
+        // Both branches act, whichever way the flag goes.
+        assert_eq!(
+            prefilter(
+                r#"(if (flagged "a") (if (flagged "b") (flag! "x") (flag! "y")) (flag! "z"))"#
+            ),
+            None
+        );
+        assert_eq!(
+            prefilter(r#"(halt!) (append! "d")"#).as_deref(),
+            Some("NOT ALL")
+        );
+    }
+}
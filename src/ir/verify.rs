@@ -0,0 +1,204 @@
+use anyhow::{bail, Result};
+use std::fmt;
+
+use super::{Insn, IR};
+
+// Abstractly interprets every path through the program, tracking only the kind of each stack
+// value, so that Closure::process can never find the wrong kind of value on the stack, nor leave
+// anything on it.
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Flag,
+    RecipientPattern,
+    RecipientRegex,
+    HeaderPattern,
+    Destination,
+    Cond,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Flag => "flag",
+            Kind::RecipientPattern => "recipient pattern",
+            Kind::RecipientRegex => "recipient regex",
+            Kind::HeaderPattern => "header pattern",
+            Kind::Destination => "dest",
+            Kind::Cond => "cond",
+        })
+    }
+}
+
+// What an instruction pops (top of stack first) and pushes.
+fn signature(insn: &Insn) -> (&'static [Kind], Option<Kind>) {
+    match insn {
+        Insn::LiteralFlag(_) => (&[], Some(Kind::Flag)),
+        Insn::LiteralRecipientPattern(_) => (&[], Some(Kind::RecipientPattern)),
+        Insn::LiteralRecipientRegex(_) => (&[], Some(Kind::RecipientRegex)),
+        Insn::LiteralHeaderPattern(..) => (&[], Some(Kind::HeaderPattern)),
+        Insn::LiteralDest(_) => (&[], Some(Kind::Destination)),
+
+        Insn::Flagged => (&[Kind::Flag], Some(Kind::Cond)),
+        Insn::ReceivedBy | Insn::SentBy | Insn::Sender | Insn::ReplyTo => {
+            (&[Kind::RecipientPattern], Some(Kind::Cond))
+        }
+        Insn::ReceivedByRegex => (&[Kind::RecipientRegex], Some(Kind::Cond)),
+        Insn::Header => (&[Kind::HeaderPattern], Some(Kind::Cond)),
+        Insn::Seen => (&[], Some(Kind::Cond)),
+        Insn::Or | Insn::And => (&[Kind::Cond, Kind::Cond], Some(Kind::Cond)),
+        Insn::Not => (&[Kind::Cond], Some(Kind::Cond)),
+
+        Insn::Append => (&[Kind::Destination], None),
+        Insn::Flag => (&[Kind::Flag], None),
+        Insn::Halt | Insn::Delete | Insn::Remember | Insn::Jump(_) => (&[], None),
+        Insn::JumpFalse(_) => (&[Kind::Cond], None),
+    }
+}
+
+pub(super) fn verify(ir: &IR, insns: &[Insn]) -> Result<()> {
+    for (pc, insn) in insns.iter().enumerate() {
+        let (ix, len) = match *insn {
+            Insn::LiteralFlag(ix) => (ix, ir.flags.len()),
+            Insn::LiteralRecipientPattern(ix) => (ix, ir.recipient_patterns.len()),
+            Insn::LiteralDest(ix) => (ix, ir.dests.len()),
+            _ => continue,
+        };
+        if ix >= len {
+            bail!("{:02x} {}: pool has only {} entries", pc, insn, len);
+        }
+    }
+
+    let mut seen: Vec<Option<Vec<Kind>>> = vec![None; insns.len() + 1];
+    let mut work = vec![(0, vec![])];
+
+    while let Some((pc, stack)) = work.pop() {
+        if pc > insns.len() {
+            bail!("jump to {:02x} is past the end", pc);
+        }
+        match &seen[pc] {
+            Some(prev) if *prev == stack => continue,
+            Some(prev) => bail!(
+                "{:02x} reached with stack [{}] and [{}]",
+                pc,
+                describe(prev),
+                describe(&stack)
+            ),
+            None => seen[pc] = Some(stack.clone()),
+        }
+
+        if pc == insns.len() {
+            if !stack.is_empty() {
+                bail!("program ends with [{}] on the stack", describe(&stack));
+            }
+            continue;
+        }
+
+        let insn = &insns[pc];
+        let (pops, push) = signature(insn);
+        let mut stack = stack;
+        for &expected in pops {
+            match stack.pop() {
+                Some(kind) if kind == expected => {}
+                Some(kind) => bail!("{:02x} {}: expected {}, got {}", pc, insn, expected, kind),
+                None => bail!("{:02x} {}: expected {}, stack empty", pc, insn, expected),
+            }
+        }
+        if let Some(kind) = push {
+            stack.push(kind);
+        }
+
+        match *insn {
+            Insn::Halt => {
+                if !stack.is_empty() {
+                    bail!(
+                        "{:02x} {}: [{}] left on the stack",
+                        pc,
+                        insn,
+                        describe(&stack)
+                    );
+                }
+            }
+            Insn::Jump(t) => work.push((t, stack)),
+            Insn::JumpFalse(t) => {
+                work.push((t, stack.clone()));
+                work.push((pc + 1, stack));
+            }
+            _ => work.push((pc + 1, stack)),
+        }
+    }
+
+    Ok(())
+}
+
+fn describe(stack: &[Kind]) -> String {
+    stack
+        .iter()
+        .map(|k| k.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::compile_for_test;
+
+    fn ir() -> IR {
+        compile_for_test(
+            r#"(if (flagged "Recogido") (halt!))
+               (if (received-by "fox@den.com") (append! "d0") (delete!))"#,
+            &["d0"],
+        )
+        .unwrap()
+    }
+
+    fn error(insns: &[Insn]) -> String {
+        format!("{:#}", verify(&ir(), insns).err().unwrap())
+    }
+
+    #[test]
+    fn compiled() {
+        let ir = ir();
+        verify(&ir, &ir.unoptimized).unwrap();
+        verify(&ir, &ir.insns).unwrap();
+        verify(&ir, &[]).unwrap();
+    }
+
+    #[test]
+    fn kinds() {
+        use Insn::*;
+        assert_eq!(
+            error(&[LiteralDest(0), Flagged, JumpFalse(3), Halt]),
+            "01 flagged?: expected flag, got dest"
+        );
+        assert_eq!(error(&[Not]), "00 not: expected cond, stack empty");
+        assert_eq!(
+            error(&[LiteralFlag(0)]),
+            "program ends with [flag] on the stack"
+        );
+        assert_eq!(
+            error(&[LiteralFlag(0), Halt]),
+            "01 halt!: [flag] left on the stack"
+        );
+    }
+
+    #[test]
+    fn paths() {
+        use Insn::*;
+        assert_eq!(
+            error(&[LiteralFlag(0), JumpFalse(2)]),
+            "01 jfalse 02: expected cond, got flag"
+        );
+        // The stack must be the same whichever way a jump target is reached.
+        assert_eq!(
+            error(&[Seen, JumpFalse(3), LiteralFlag(0), Delete, Flag]),
+            "03 reached with stack [flag] and []"
+        );
+        assert_eq!(error(&[Jump(5)]), "jump to 05 is past the end");
+        assert_eq!(
+            error(&[LiteralDest(3), Append]),
+            "00 d3: pool has only 1 entries"
+        );
+    }
+}
@@ -0,0 +1,672 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use futures::StreamExt;
+use inotify::{EventMask, EventStream, Inotify, WatchMask};
+use log::{info, trace};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    endpoint::{
+        EndpointFlagger, EndpointReader, EndpointSelector, EndpointWriter, IdleResult, Mailbox,
+        Message,
+    },
+    ir::SearchKey,
+    shutdown::Shutdown,
+};
+
+// Maildir info flags and the IMAP flags they stand for, in the ASCII order they're written in.
+// Lowercase letters are keywords, numbered by dovecot-keywords as Dovecot does.
+const FLAGS: [(char, &str); 5] = [
+    ('D', "\\Draft"),
+    ('F', "\\Flagged"),
+    ('R', "\\Answered"),
+    ('S', "\\Seen"),
+    ('T', "\\Deleted"),
+];
+
+// The UID list is rewritten whole whenever it changes:
+//
+//   V<uidvalidity> N<next uid>
+//   <uid> <base name>
+//
+// where the base name is the file name up to its ":2," info.
+const UIDLIST: &str = "recogedor-uidlist";
+const KEYWORDS: &str = "dovecot-keywords";
+
+#[derive(Clone)]
+pub(crate) struct MaildirEndpoint {
+    name: String,
+    path: PathBuf,
+}
+
+impl MaildirEndpoint {
+    pub(crate) fn from_config(name: &str, table: &toml::Table) -> Result<MaildirEndpoint> {
+        let path = table
+            .get("path")
+            .with_context(|| format!("{} missing maildir path", name))?
+            .as_str()
+            .with_context(|| format!("{} maildir path not string", name))?
+            .into();
+        Ok(MaildirEndpoint {
+            name: name.to_string(),
+            path,
+        })
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn connect(&self) -> MaildirEndpointClient {
+        MaildirEndpointClient {
+            name: self.name.clone(),
+            root: self.path.clone(),
+            folder: None,
+        }
+    }
+}
+
+pub(crate) struct MaildirEndpointClient {
+    name: String,
+    root: PathBuf,
+    folder: Option<Folder>,
+}
+
+impl MaildirEndpointClient {
+    // Maildir++: INBOX is the maildir itself, and every other folder a dot-prefixed maildir
+    // inside it, with hierarchy separated by dots too.
+    fn folder_dir(&self, folder: &str) -> PathBuf {
+        if folder.eq_ignore_ascii_case("INBOX") {
+            self.root.clone()
+        } else {
+            self.root.join(format!(".{}", folder.replace('/', ".")))
+        }
+    }
+
+    // Delivering to a folder creates it if it doesn't exist yet.
+    fn create_folder(&self, folder: &str) -> Result<PathBuf> {
+        let dir = self.folder_dir(folder);
+        for sub in ["tmp", "new", "cur"] {
+            let sub = dir.join(sub);
+            fs::create_dir_all(&sub).with_context(|| format!("creating {:?}", sub))?;
+        }
+        if dir != self.root {
+            let marker = dir.join("maildirfolder");
+            if !marker.exists() {
+                File::create(&marker).with_context(|| format!("creating {:?}", marker))?;
+            }
+        }
+        Ok(dir)
+    }
+
+    fn open(&mut self, folder: &str, writable: bool) -> Result<Mailbox> {
+        trace!("[{}] opening {:?} ...", self.name, folder);
+        let dir = self.folder_dir(folder);
+        if !dir.join("cur").is_dir() || !dir.join("new").is_dir() {
+            bail!("{} no such maildir folder {}", self.name, folder);
+        }
+        let folder = Folder::open(dir, writable)?;
+        let mailbox = Mailbox {
+            uid_validity: folder.uid_validity,
+        };
+        self.folder = Some(folder);
+        Ok(mailbox)
+    }
+
+    fn selected(&mut self) -> Result<&mut Folder> {
+        self.folder.as_mut().context("no folder selected")
+    }
+}
+
+#[async_trait]
+impl EndpointSelector for MaildirEndpointClient {
+    async fn select(&mut self, folder: &str) -> Result<Mailbox> {
+        self.open(folder, true)
+    }
+
+    async fn examine(&mut self, folder: &str) -> Result<Mailbox> {
+        self.open(folder, false)
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        self.folder = None;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EndpointReader for MaildirEndpointClient {
+    // Waits for a file to turn up in new/ or cur/ which isn't one we already know by another name.
+    // Renames which led to an expunge are queued behind it, so files which are gone don't count.
+    async fn idle(&mut self, shutdown: &mut Shutdown) -> Result<IdleResult> {
+        let name = self.name.clone();
+        let Folder {
+            dir, events, uids, ..
+        } = self.selected()?;
+        let events = events.as_mut().context("folder examined, not selected")?;
+        loop {
+            trace!("[{}] waiting ...", name);
+            let event = tokio::select! {
+                event = events.next() => event.context("inotify stream ended")??,
+                _ = shutdown.wait() => return Ok(IdleResult::Shutdown),
+            };
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                trace!("[{}] inotify queue overflowed", name);
+                return Ok(IdleResult::Exists);
+            }
+            let Some(file) = event.name.as_ref().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if !file.starts_with('.')
+                && !uids.contains_key(base_name(file))
+                && ["new", "cur"]
+                    .iter()
+                    .any(|sub| dir.join(sub).join(file).exists())
+            {
+                trace!("[{}] got {:?}", name, file);
+                return Ok(IdleResult::Exists);
+            }
+        }
+    }
+
+    // Local reads are cheap enough that the prefilter isn't worth evaluating; the script checks
+    // everything anyway.
+    async fn read(&mut self, min_uid: u32, _search: Option<&SearchKey>) -> Result<Vec<Message>> {
+        let folder = self.selected()?;
+        folder.scan()?;
+        let mut result = vec![];
+        for (&uid, path) in folder.paths.range(min_uid..) {
+            let body = match fs::read(path) {
+                Ok(body) => body,
+                // Expunged by someone else since the scan.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err).with_context(|| format!("reading {:?}", path)),
+            };
+            result.push(Message::from_rfc822(uid, body, folder.flags(path)));
+        }
+        Ok(result)
+    }
+
+    async fn fetch_body(&mut self, uid: u32) -> Result<Vec<u8>> {
+        let folder = self.selected()?;
+        let path = folder.path(uid)?;
+        fs::read(&path).with_context(|| format!("reading {:?}", path))
+    }
+}
+
+#[async_trait]
+impl EndpointFlagger for MaildirEndpointClient {
+    async fn flag(&mut self, uid: u32, flag: &str) -> Result<()> {
+        info!("[{}] flagging {:?} ...", self.name, flag);
+        let folder = self.selected()?;
+        let letter = match FLAGS.iter().find(|(_, f)| *f == flag) {
+            Some(&(letter, _)) => letter,
+            None if flag.starts_with('\\') => bail!("can't store {} in a maildir", flag),
+            None => folder.keyword(flag)?,
+        };
+        folder.add_flag(uid, letter)
+    }
+
+    async fn delete(&mut self, uid: u32) -> Result<()> {
+        self.flag(uid, r"\Deleted").await
+    }
+
+    async fn expunge(&mut self) -> Result<()> {
+        info!("[{}] expunging ...", self.name);
+        let folder = self.selected()?;
+        folder.scan()?;
+        let mut expunged = vec![];
+        for (&uid, path) in &folder.paths {
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+            if info(name).contains('T') {
+                match fs::remove_file(path) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                        return Err(err).with_context(|| format!("removing {:?}", path))
+                    }
+                    _ => expunged.push((uid, base_name(name).to_string())),
+                }
+            }
+        }
+        if expunged.is_empty() {
+            return Ok(());
+        }
+        for (uid, base) in expunged {
+            folder.paths.remove(&uid);
+            folder.uids.remove(&base);
+        }
+        folder.write_uidlist()
+    }
+}
+
+#[async_trait]
+impl EndpointWriter for MaildirEndpointClient {
+    // Written into tmp/ and then renamed, so readers never see a partial message.  Mail with
+    // flags Maildir can express goes straight into cur/ with them; the rest is new.
+    async fn append(&mut self, folder: &str, message: &Message) -> Result<()> {
+        let dir = self.create_folder(folder)?;
+        let name = unique_name();
+        let tmp = dir.join("tmp").join(&name);
+        let info: String = FLAGS
+            .iter()
+            .filter(|(_, flag)| message.flagged(flag))
+            .map(|&(c, _)| c)
+            .collect();
+        let path = if info.is_empty() {
+            dir.join("new").join(&name)
+        } else {
+            dir.join("cur").join(format!("{}:2,{}", name, info))
+        };
+
+        info!("[{}] delivering to {:?} ...", self.name, path);
+        let result = write_synced(&tmp, message.body()?).and_then(|_| fs::rename(&tmp, &path));
+        if let Err(err) = result {
+            _ = fs::remove_file(&tmp);
+            return Err(err).with_context(|| format!("delivering to {:?}", path));
+        }
+        File::open(path.parent().unwrap())
+            .and_then(|d| d.sync_all())
+            .with_context(|| format!("syncing {:?}", path.parent().unwrap()))?;
+        Ok(())
+    }
+}
+
+// A selected folder: the UIDs given to its files, where those files are now, and (unless only
+// examined) a watch for new ones.
+struct Folder {
+    dir: PathBuf,
+    writable: bool,
+    uid_validity: u32,
+    next_uid: u32,
+    uids: HashMap<String, u32>,
+    paths: BTreeMap<u32, PathBuf>,
+    // Base names with a UID whose files the last scan didn't find.
+    missing: HashSet<String>,
+    keywords: Vec<String>,
+    events: Option<EventStream<[u8; 4096]>>,
+}
+
+impl Folder {
+    fn open(dir: PathBuf, writable: bool) -> Result<Folder> {
+        // Watch before the first scan, so nothing delivered in between is missed.
+        let events = if writable {
+            let inotify = Inotify::init().context("initialising inotify")?;
+            for sub in ["new", "cur"] {
+                inotify
+                    .watches()
+                    .add(dir.join(sub), WatchMask::CREATE | WatchMask::MOVED_TO)
+                    .with_context(|| format!("watching {:?}", dir.join(sub)))?;
+            }
+            Some(
+                inotify
+                    .into_event_stream([0; 4096])
+                    .context("reading inotify events")?,
+            )
+        } else {
+            None
+        };
+
+        let uidlist = dir.join(UIDLIST);
+        let mut uids = HashMap::new();
+        let (uid_validity, next_uid) = if uidlist.exists() {
+            let text =
+                fs::read_to_string(&uidlist).with_context(|| format!("reading {:?}", uidlist))?;
+            let mut lines = text.lines();
+            let header = lines.next().unwrap_or_default();
+            let parse_header = || -> Option<(u32, u32)> {
+                let (v, n) = header.split_once(' ')?;
+                Some((
+                    v.strip_prefix('V')?.parse().ok()?,
+                    n.strip_prefix('N')?.parse().ok()?,
+                ))
+            };
+            let header =
+                parse_header().with_context(|| format!("{:?} line 1 malformed", uidlist))?;
+            for (lineno, line) in lines.enumerate() {
+                let (uid, base) = line
+                    .split_once(' ')
+                    .and_then(|(uid, base)| Some((uid.parse().ok()?, base)))
+                    .with_context(|| format!("{:?} line {} malformed", uidlist, lineno + 2))?;
+                uids.insert(base.to_string(), uid);
+            }
+            header
+        } else {
+            // A new UID list starts a new UIDVALIDITY, so UIDs from any earlier one aren't reused.
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            (now.as_secs() as u32, 1)
+        };
+
+        let keywords_path = dir.join(KEYWORDS);
+        let mut keywords = vec![];
+        if keywords_path.exists() {
+            let text = fs::read_to_string(&keywords_path)
+                .with_context(|| format!("reading {:?}", keywords_path))?;
+            for (lineno, line) in text.lines().enumerate() {
+                let (ix, keyword) = line
+                    .split_once(' ')
+                    .and_then(|(ix, keyword)| Some((ix.parse::<usize>().ok()?, keyword)))
+                    .filter(|&(ix, _)| ix < 26)
+                    .with_context(|| {
+                        format!("{:?} line {} malformed", keywords_path, lineno + 1)
+                    })?;
+                if keywords.len() <= ix {
+                    keywords.resize(ix + 1, String::new());
+                }
+                keywords[ix] = keyword.to_string();
+            }
+        }
+
+        Ok(Folder {
+            dir,
+            writable,
+            uid_validity,
+            next_uid,
+            uids,
+            paths: BTreeMap::new(),
+            missing: HashSet::new(),
+            keywords,
+            events,
+        })
+    }
+
+    // Finds every file in new/ and cur/, giving a UID to any not seen before.  Files are ordered
+    // by name for this, which for conforming names is by delivery time.
+    //
+    // A file being moved from new/ to cur/, or renamed within cur/, can be missed by a scan.  Like
+    // Dovecot, we list again if new/ changed while we were listing it, and a file missing from the
+    // UID list only loses its UID once a second scan hasn't found it either (or we expunge it), so
+    // it can't come back under a fresh UID and be processed twice.
+    fn scan(&mut self) -> Result<()> {
+        let new = self.dir.join("new");
+        let mut found = BTreeMap::new();
+        for attempt in 1.. {
+            let before = fs::metadata(&new).and_then(|m| m.modified()).ok();
+            found.clear();
+            for sub in ["new", "cur"] {
+                let dir = self.dir.join(sub);
+                for entry in fs::read_dir(&dir).with_context(|| format!("reading {:?}", dir))? {
+                    let entry = entry.with_context(|| format!("reading {:?}", dir))?;
+                    let Ok(name) = entry.file_name().into_string() else {
+                        continue;
+                    };
+                    if !name.starts_with('.') {
+                        // Seen in both if moved mid-scan; cur/ is listed last and is the newer.
+                        found.insert(base_name(&name).to_string(), entry.path());
+                    }
+                }
+            }
+            let after = fs::metadata(&new).and_then(|m| m.modified()).ok();
+            if before == after || attempt == 5 {
+                break;
+            }
+            trace!("{:?} changed during scan; rescanning", new);
+        }
+
+        let mut changed = false;
+        let mut paths = BTreeMap::new();
+        for (base, path) in found {
+            let uid = match self.uids.get(&base) {
+                Some(&uid) => uid,
+                None => {
+                    changed = true;
+                    self.next_uid += 1;
+                    self.uids.insert(base.clone(), self.next_uid - 1);
+                    self.next_uid - 1
+                }
+            };
+            paths.insert(uid, path);
+        }
+        let mut missing = HashSet::new();
+        self.uids.retain(|base, uid| {
+            if paths.contains_key(uid) {
+                return true;
+            }
+            if self.missing.contains(base) {
+                changed = true;
+                return false;
+            }
+            missing.insert(base.clone());
+            true
+        });
+        self.paths = paths;
+        self.missing = missing;
+
+        if changed {
+            self.write_uidlist()?;
+        }
+        Ok(())
+    }
+
+    fn write_uidlist(&self) -> Result<()> {
+        if !self.writable {
+            return Ok(());
+        }
+        let mut sorted: Vec<_> = self.uids.iter().map(|(base, &uid)| (uid, base)).collect();
+        sorted.sort();
+        let mut text = format!("V{} N{}\n", self.uid_validity, self.next_uid);
+        for (uid, base) in sorted {
+            text.push_str(&format!("{} {}\n", uid, base));
+        }
+        replace(&self.dir.join(UIDLIST), text.as_bytes())
+    }
+
+    fn path(&mut self, uid: u32) -> Result<PathBuf> {
+        if !self.paths.contains_key(&uid) {
+            self.scan()?;
+        }
+        self.paths
+            .get(&uid)
+            .cloned()
+            .with_context(|| format!("uid {} not found", uid))
+    }
+
+    fn flags(&self, path: &Path) -> HashSet<String> {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        info(name)
+            .chars()
+            .filter_map(|c| match c {
+                'a'..='z' => self
+                    .keywords
+                    .get((c as u8 - b'a') as usize)
+                    .filter(|k| !k.is_empty())
+                    .cloned(),
+                _ => FLAGS
+                    .iter()
+                    .find(|&&(letter, _)| letter == c)
+                    .map(|(_, flag)| flag.to_string()),
+            })
+            .collect()
+    }
+
+    // The letter for a keyword, numbering it if it's new.
+    fn keyword(&mut self, keyword: &str) -> Result<char> {
+        let ix = match self.keywords.iter().position(|k| k == keyword) {
+            Some(ix) => ix,
+            None => {
+                let ix = match self.keywords.iter().position(|k| k.is_empty()) {
+                    Some(ix) => ix,
+                    None if self.keywords.len() < 26 => {
+                        self.keywords.push(String::new());
+                        self.keywords.len() - 1
+                    }
+                    None => bail!("no room for keyword {} in {:?}", keyword, self.dir),
+                };
+                self.keywords[ix] = keyword.to_string();
+                let mut text = String::new();
+                for (ix, keyword) in self.keywords.iter().enumerate() {
+                    if !keyword.is_empty() {
+                        text.push_str(&format!("{} {}\n", ix, keyword));
+                    }
+                }
+                replace(&self.dir.join(KEYWORDS), text.as_bytes())?;
+                ix
+            }
+        };
+        Ok((b'a' + ix as u8) as char)
+    }
+
+    // Flagging moves mail out of new/, since only cur/ names carry info.
+    fn add_flag(&mut self, uid: u32, letter: char) -> Result<()> {
+        // The file may have been renamed by someone else since the last scan; look again once.
+        for retry in [false, true] {
+            if retry {
+                self.scan()?;
+            }
+            let path = self.path(uid)?;
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+            let mut letters: Vec<char> = info(name).chars().collect();
+            if letters.contains(&letter) && path.parent() == Some(&self.dir.join("cur")) {
+                return Ok(());
+            }
+            letters.push(letter);
+            letters.sort_unstable();
+            letters.dedup();
+            let to = self.dir.join("cur").join(format!(
+                "{}:2,{}",
+                base_name(name),
+                letters.into_iter().collect::<String>()
+            ));
+            match fs::rename(&path, &to) {
+                Ok(()) => {
+                    self.paths.insert(uid, to);
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound && !retry => {}
+                Err(err) => return Err(err).with_context(|| format!("renaming {:?}", path)),
+            }
+        }
+        unreachable!()
+    }
+}
+
+fn base_name(name: &str) -> &str {
+    name.split_once(':').map_or(name, |(base, _)| base)
+}
+
+fn info(name: &str) -> &str {
+    name.split_once(":2,").map_or("", |(_, info)| info)
+}
+
+// Atomically replaces a small bookkeeping file.
+fn replace(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    write_synced(&tmp, data)
+        .and_then(|_| fs::rename(&tmp, path))
+        .with_context(|| format!("writing {:?}", path))
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+// time.MmicrosPpidQn.host, unique so long as the clock doesn't go backwards.
+fn unique_name() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}.M{}P{}Q{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed),
+        hostname()
+    )
+}
+
+fn hostname() -> String {
+    let name = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+    let name = name.trim();
+    if name.is_empty() {
+        return "localhost".to_string();
+    }
+    name.replace('/', "\\057").replace(':', "\\072")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Dir {
+            let dir = env::temp_dir().join(format!("recogedor-maildir-{}-{}", name, process::id()));
+            _ = fs::remove_dir_all(&dir);
+            for sub in ["tmp", "new", "cur"] {
+                fs::create_dir_all(dir.join(sub)).unwrap();
+            }
+            Dir(dir)
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn uids(folder: &Folder) -> Vec<(u32, String)> {
+        let mut uids: Vec<_> = folder.uids.iter().map(|(b, &u)| (u, b.clone())).collect();
+        uids.sort();
+        uids
+    }
+
+    // A writable folder's inotify stream needs a runtime.
+    #[tokio::test]
+    async fn stable_uids() {
+        let dir = Dir::new("stable");
+        let d = &dir.0;
+        fs::write(d.join("new/1.a.host"), "").unwrap();
+        fs::write(d.join("new/2.b.host"), "").unwrap();
+        let mut folder = Folder::open(d.clone(), true).unwrap();
+        folder.scan().unwrap();
+        assert_eq!(folder.paths.len(), 2);
+
+        // Renamed out from under one scan: it keeps its UID when it turns up again.
+        fs::rename(d.join("new/2.b.host"), d.join("tmp/2.b.host")).unwrap();
+        folder.scan().unwrap();
+        assert_eq!(folder.paths.keys().collect::<Vec<_>>(), [&1]);
+        fs::rename(d.join("tmp/2.b.host"), d.join("cur/2.b.host:2,S")).unwrap();
+        fs::write(d.join("new/3.c.host"), "").unwrap();
+        folder.scan().unwrap();
+        assert_eq!(folder.paths[&2], d.join("cur/2.b.host:2,S"));
+        assert_eq!(folder.paths[&3], d.join("new/3.c.host"));
+
+        // Gone for two scans in a row: really gone.
+        fs::remove_file(d.join("new/1.a.host")).unwrap();
+        folder.scan().unwrap();
+        assert!(folder.uids.contains_key("1.a.host"));
+        folder.scan().unwrap();
+        assert!(!folder.uids.contains_key("1.a.host"));
+
+        let reopened = Folder::open(d.clone(), true).unwrap();
+        assert_eq!(
+            uids(&reopened),
+            [(2, "2.b.host".to_string()), (3, "3.c.host".to_string())]
+        );
+        assert_eq!(reopened.next_uid, 4);
+    }
+}
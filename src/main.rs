@@ -1,18 +1,38 @@
-use anyhow::{Context, Result};
-use clap::{arg, command, value_parser};
+use anyhow::{bail, Context, Result};
+use clap::{arg, command, value_parser, ArgAction, Command};
 use futures::future::try_join_all;
-use log::{debug, info};
+use log::{debug, info, warn};
 use std::path::PathBuf;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::{sleep, timeout},
+};
 
 mod ast;
+mod backoff;
+mod check;
 mod config;
 mod endpoint;
+mod fixture;
+mod header;
 mod imap;
 mod ir;
+mod maildir;
+mod mbox;
+mod oauth;
+mod plan;
+mod recorder;
 mod script;
+mod secret;
+mod shutdown;
+mod state;
+mod test;
+mod tunnel;
 
+use backoff::Backoff;
 use config::Config;
-use endpoint::{Endpoint, IdleResult, SourceEndpoint};
+use endpoint::{IdleResult, Mailbox, SourceEndpoint};
+use shutdown::Shutdown;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -22,65 +42,226 @@ async fn main() -> Result<()> {
         .arg(
             arg!(-c --config <FILE> "Path to config.toml")
                 .required(false)
+                .global(true)
                 .value_parser(value_parser!(PathBuf)),
         )
         .arg(arg!(-n --"dry-run" "Check that the config parses, but don't run."))
+        .subcommand(Command::new("plan").about(
+            "Show what the script would do with the mail in the source folders, without changing anything.",
+        ))
+        .subcommand(
+            Command::new("test")
+                .about("Run the script on mail from .eml or mbox files and print what it would do.")
+                .arg(
+                    arg!(-f --flag <FLAG> "Flag the mail already has; may be repeated.")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    arg!(-r --recipient <ADDRESS> "Extra envelope recipient; may be repeated.")
+                        .action(ArgAction::Append),
+                )
+                .arg(arg!(--folder <FOLDER> "Source folder the mail is in (default: the first)."))
+                .arg(
+                    arg!(<FILES> ... "Mail to run the script on.")
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
         .get_matches();
     let config_path = matches
         .get_one::<PathBuf>("config")
         .cloned()
         .unwrap_or("config.toml".into());
-    let config = config::from_file(&config_path)
+    let mut config = config::from_file(&config_path)
         .with_context(|| format!("reading {}", config_path.display()))?;
     info!("config read OK");
     debug!("{}", config.ir);
 
-    if !*matches.get_one::<bool>("dry-run").unwrap_or(&false) {
-        let mut futs = vec![];
-        for folder in &config.folders {
-            futs.push(run(&config, folder));
+    match matches.subcommand() {
+        Some(("plan", _)) => {
+            config.open_state(true)?;
+            return plan::run(&config).await;
+        }
+        Some(("test", matches)) => return test::run(&config, matches).await,
+        _ => {}
+    }
+
+    if *matches.get_one::<bool>("dry-run").unwrap_or(&false) {
+        return check::run(&config).await;
+    }
+
+    config.open_state(false)?;
+
+    let (trigger, shutdown) = shutdown::channel();
+    let mut futs = vec![];
+    for folder in &config.folders {
+        futs.push(supervise(&config, folder, shutdown.clone()));
+    }
+    let work = try_join_all(futs);
+    tokio::pin!(work);
+
+    let mut sigterm = signal(SignalKind::terminate()).context("installing SIGTERM handler")?;
+    let mut sigint = signal(SignalKind::interrupt()).context("installing SIGINT handler")?;
+    tokio::select! {
+        r = &mut work => {
+            r?;
+            return Ok(());
         }
-        try_join_all(futs).await?;
+        _ = sigterm.recv() => {}
+        _ = sigint.recv() => {}
     }
 
+    info!(
+        "shutting down (grace period {}s) ...",
+        config.grace_period.as_secs()
+    );
+    trigger.trigger();
+    if timeout(config.grace_period, work).await.is_err() {
+        bail!("grace period expired before shutdown finished");
+    }
+    info!("shut down cleanly");
+
     Ok(())
 }
 
-async fn prep_src(endpoint: &Endpoint, folder: &str) -> Result<Box<dyn SourceEndpoint>> {
-    let mut src = endpoint
+async fn supervise(config: &Config, folder: &str, mut shutdown: Shutdown) -> Result<()> {
+    let mut backoff = Backoff::new();
+    let mut cursor = None;
+    loop {
+        let err = match run(config, folder, &mut cursor, &mut backoff, &mut shutdown).await {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        if shutdown.requested() {
+            warn!("[{}] failed while shutting down: {:#}", folder, err);
+            return Ok(());
+        }
+        let delay = backoff.next();
+        warn!("[{}] failed: {:#}", folder, err);
+        info!(
+            "[{}] reconnecting in {:.1}s ...",
+            folder,
+            delay.as_secs_f64()
+        );
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = shutdown.wait() => return Ok(()),
+        }
+    }
+}
+
+async fn prep_src(
+    config: &Config,
+    folder: &str,
+    cursor: &mut Option<(u32, u32)>,
+) -> Result<(Box<dyn SourceEndpoint>, Mailbox)> {
+    debug!("[{}] connecting ...", folder);
+    let mut src = config
+        .src
         .connect_source()
         .await
         .context("connecting source")?;
 
-    src.select(folder).await.context("selecting folder")?;
+    let mailbox = src.select(folder).await.context("selecting folder")?;
+    info!("[{}] connected", folder);
 
-    Ok(src)
+    if let Some((uid_validity, _)) = *cursor {
+        if uid_validity != mailbox.uid_validity {
+            info!("[{}] UIDVALIDITY changed; rescanning", folder);
+            *cursor = None;
+        }
+    }
+
+    Ok((src, mailbox))
 }
 
-async fn run(config: &Config, folder: &str) -> Result<()> {
-    let mut src = prep_src(&config.src, folder).await?;
+// cursor is the UIDVALIDITY and highest UID processed so far, kept across reconnects.
+async fn run(
+    config: &Config,
+    folder: &str,
+    cursor: &mut Option<(u32, u32)>,
+    backoff: &mut Backoff,
+    shutdown: &mut Shutdown,
+) -> Result<()> {
+    let (mut src, mut mailbox) = prep_src(config, folder, cursor).await?;
 
-    loop {
-        let mut closure = config.ir.closure(folder);
+    'scan: loop {
+        let mut closure = config
+            .ir
+            .closure(folder, mailbox.uid_validity, config.state.as_ref());
+
+        let min_uid = if config.full_rescan {
+            1
+        } else {
+            state::last_uid(config.state.as_ref(), folder, *cursor, mailbox.uid_validity)
+                .map_or(1, |uid| uid + 1)
+        };
+        let mut failure = None;
+        for mail in src
+            .read(min_uid, config.ir.search())
+            .await
+            .context("reading")?
+        {
+            if shutdown.requested() {
+                break;
+            }
+            let handled = config
+                .state
+                .as_ref()
+                .is_some_and(|state| state.handled(folder, mailbox.uid_validity, mail.uid));
+            if !handled {
+                if let Err(err) = closure.process(&mail, &mut src).await {
+                    failure = Some(err);
+                    break;
+                }
+            }
+            if cursor.is_none_or(|(_, uid)| mail.uid > uid) {
+                *cursor = Some((mailbox.uid_validity, mail.uid));
+            }
+        }
+
+        // Mail deleted before a failure is still expunged, rather than left for a later run.
+        let needs_expunge = closure.needs_expunge();
+        let finished = closure.finish().await;
+        let expunged = if needs_expunge {
+            src.expunge().await
+        } else {
+            Ok(())
+        };
+        if let Some(err) = failure {
+            if let Err(tidy) = finished.and(expunged) {
+                warn!("[{}] tidying up after failure: {:#}", folder, tidy);
+            }
+            return Err(err);
+        }
+        finished?;
+        expunged?;
 
-        for mail in src.read().await.context("reading")? {
-            closure.process(&mail, &mut src).await?;
+        if let (Some(state), Some((uid_validity, uid))) = (&config.state, *cursor) {
+            if !config.full_rescan {
+                state.set_last_uid(folder, uid_validity, uid)?;
+            }
         }
+        backoff.reset();
 
-        let needs_expunge = closure.finish().await?;
-        if needs_expunge {
-            src.expunge().await?;
+        if shutdown.requested() {
+            break 'scan;
         }
 
         'idle: loop {
-            match src.idle().await.context("IDLEing")? {
+            match src.idle(shutdown).await.context("IDLEing")? {
                 IdleResult::Exists => break 'idle,
                 IdleResult::ReIdle => continue 'idle,
                 IdleResult::ReConnect => {
-                    src = prep_src(&config.src, folder).await?;
+                    info!("[{}] server hung up", folder);
+                    (src, mailbox) = prep_src(config, folder, cursor).await?;
                     break 'idle;
                 }
+                IdleResult::Shutdown => break 'scan,
             }
         }
     }
+
+    src.disconnect().await.context("logging out")?;
+    info!("[{}] logged out", folder);
+    Ok(())
 }
@@ -0,0 +1,497 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use flate2::{write::GzEncoder, Compression};
+use log::{info, warn};
+use regex::Regex;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::endpoint::{EndpointSelector, EndpointWriter, Mailbox, Message};
+
+// Each folder is archived to <path>/<folder>.mbox, e.g. archive/Lists/rust.mbox.  With date
+// rotation the period is part of the name (rust-2026-10.mbox), and a file which grows past
+// max_size is renamed aside with a number (rust-2026-10.1.mbox).  Rotated files are gzipped if
+// asked.
+
+#[derive(Clone, Copy)]
+enum Rotate {
+    Yearly,
+    Monthly,
+    Daily,
+}
+
+impl Rotate {
+    fn period(self, secs: u64) -> String {
+        let (y, m, d) = civil(secs).0;
+        match self {
+            Rotate::Yearly => format!("{:04}", y),
+            Rotate::Monthly => format!("{:04}-{:02}", y, m),
+            Rotate::Daily => format!("{:04}-{:02}-{:02}", y, m, d),
+        }
+    }
+
+    fn pattern(self) -> &'static str {
+        match self {
+            Rotate::Yearly => r"\d{4}",
+            Rotate::Monthly => r"\d{4}-\d{2}",
+            Rotate::Daily => r"\d{4}-\d{2}-\d{2}",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct MboxEndpoint {
+    name: String,
+    path: PathBuf,
+    rotate: Option<Rotate>,
+    max_size: Option<u64>,
+    compress: bool,
+}
+
+impl MboxEndpoint {
+    pub(crate) fn from_config(name: &str, table: &toml::Table) -> Result<MboxEndpoint> {
+        let path = table
+            .get("path")
+            .with_context(|| format!("{} missing mbox path", name))?
+            .as_str()
+            .with_context(|| format!("{} mbox path not string", name))?
+            .into();
+        let rotate = match table.get("rotate") {
+            None => None,
+            Some(v) => match v
+                .as_str()
+                .with_context(|| format!("{} mbox rotate not string", name))?
+            {
+                "yearly" => Some(Rotate::Yearly),
+                "monthly" => Some(Rotate::Monthly),
+                "daily" => Some(Rotate::Daily),
+                rotate => bail!("{} unknown mbox rotate {}", name, rotate),
+            },
+        };
+        let max_size = match table.get("max_size") {
+            None => None,
+            Some(v) => Some(
+                v.as_integer()
+                    .with_context(|| format!("{} mbox max_size should be integer?", name))?
+                    .try_into()
+                    .with_context(|| format!("{} mbox max_size should be non-negative", name))?,
+            ),
+        };
+        let compress = match table.get("compress") {
+            None => false,
+            Some(v) => v
+                .as_bool()
+                .with_context(|| format!("{} mbox compress should be bool?", name))?,
+        };
+        if compress && rotate.is_none() && max_size.is_none() {
+            bail!("{} mbox compress needs rotate or max_size", name);
+        }
+        Ok(MboxEndpoint {
+            name: name.to_string(),
+            path,
+            rotate,
+            max_size,
+            compress,
+        })
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn connect(&self) -> MboxEndpointClient {
+        MboxEndpointClient {
+            endpoint: self.clone(),
+        }
+    }
+}
+
+pub(crate) struct MboxEndpointClient {
+    endpoint: MboxEndpoint,
+}
+
+impl MboxEndpointClient {
+    // The directory a folder's files go in, and their names' common stem.
+    fn folder(&self, folder: &str) -> Result<(PathBuf, String)> {
+        let mut parts: Vec<&str> = folder.split('/').collect();
+        if parts
+            .iter()
+            .any(|p| p.is_empty() || *p == "." || *p == "..")
+        {
+            bail!("{} can't archive folder {:?}", self.endpoint.name, folder);
+        }
+        let stem = parts.pop().unwrap().to_string();
+        Ok((
+            parts
+                .iter()
+                .fold(self.endpoint.path.clone(), |d, p| d.join(p)),
+            stem,
+        ))
+    }
+
+    // Opens and locks the file currently being archived to, rotating it first if it's full.
+    // Returns whether it's new, so older files may need compressing.
+    fn open(&self, dir: &Path, stem: &str, secs: u64) -> Result<(File, PathBuf, bool)> {
+        let name = match self.endpoint.rotate {
+            Some(rotate) => format!("{}-{}", stem, rotate.period(secs)),
+            None => stem.to_string(),
+        };
+        let path = dir.join(format!("{}.mbox", name));
+        let mut rotated = false;
+        loop {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("opening {:?}", path))?;
+            file.lock().with_context(|| format!("locking {:?}", path))?;
+            // Someone else may have rotated it while we waited for the lock.
+            if !same_file(&file, &path)? {
+                continue;
+            }
+            let len = file.metadata()?.len();
+            match self.endpoint.max_size {
+                Some(max_size) if len > 0 && len >= max_size => {
+                    let aside = (1..)
+                        .map(|n| dir.join(format!("{}.{}.mbox", name, n)))
+                        .find(|p| !p.exists() && !gz(p).exists())
+                        .unwrap();
+                    info!(
+                        "[{}] rotating {:?} to {:?}",
+                        self.endpoint.name, path, aside
+                    );
+                    fs::rename(&path, &aside).with_context(|| format!("rotating {:?}", path))?;
+                    rotated = true;
+                }
+                _ => return Ok((file, path, rotated || len == 0)),
+            }
+        }
+    }
+
+    // Gzips every rotated file of this folder's, e.g. last month's and any rotated for size.
+    fn compress(&self, dir: &Path, stem: &str, current: &Path) -> Result<()> {
+        let rotated = Regex::new(&format!(
+            r"^(?<original>{}{}(\.\d+)?\.mbox)(?<tmp>\.gz\.tmp)?$",
+            regex::escape(stem),
+            self.endpoint
+                .rotate
+                .map_or(String::new(), |r| format!("-{}", r.pattern()))
+        ))
+        .unwrap();
+        for entry in fs::read_dir(dir).with_context(|| format!("reading {:?}", dir))? {
+            let path = entry.with_context(|| format!("reading {:?}", dir))?.path();
+            let Some(caps) = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| rotated.captures(n))
+            else {
+                continue;
+            };
+            if caps.name("tmp").is_some() {
+                // Left by a compression which didn't finish.  If the original is still there it's
+                // compressed again (over this) below; otherwise nothing will ever finish it.
+                if !dir.join(&caps["original"]).exists() {
+                    info!("[{}] removing {:?} ...", self.endpoint.name, path);
+                    match fs::remove_file(&path) {
+                        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                            return Err(err).with_context(|| format!("removing {:?}", path))
+                        }
+                        // Or finished by compressing its original earlier in this loop.
+                        _ => {}
+                    }
+                }
+            } else if path != current {
+                info!("[{}] compressing {:?} ...", self.endpoint.name, path);
+                compress(&path).with_context(|| format!("compressing {:?}", path))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EndpointSelector for MboxEndpointClient {
+    async fn select(&mut self, folder: &str) -> Result<Mailbox> {
+        self.folder(folder)?;
+        // Only sources care about UIDs.
+        Ok(Mailbox { uid_validity: 1 })
+    }
+
+    async fn examine(&mut self, folder: &str) -> Result<Mailbox> {
+        self.select(folder).await
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EndpointWriter for MboxEndpointClient {
+    // Appended whole under an exclusive flock, and cut back off if it couldn't all be written, so
+    // the archive never holds half a message.
+    async fn append(&mut self, folder: &str, message: &Message) -> Result<()> {
+        let (dir, stem) = self.folder(folder)?;
+        fs::create_dir_all(&dir).with_context(|| format!("creating {:?}", dir))?;
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let (mut file, path, fresh) = self.open(&dir, &stem, secs)?;
+        info!("[{}] archiving to {:?} ...", self.endpoint.name, path);
+        let record = record(message, secs)?;
+        let len = file.metadata()?.len();
+        if let Err(err) = file.write_all(&record).and_then(|_| file.sync_data()) {
+            _ = file.set_len(len);
+            return Err(err).with_context(|| format!("writing {:?}", path));
+        }
+        drop(file);
+
+        if fresh && self.endpoint.compress {
+            // The mail's safely archived; failing to tidy up shouldn't make it be sent again.
+            if let Err(err) = self.compress(&dir, &stem, &path) {
+                warn!("[{}] {:#}", self.endpoint.name, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+// mboxrd: a "From " line, the message with any (>*)From line quoted by another >, and a blank
+// line.  Line endings follow the message's.
+fn record(message: &Message, secs: u64) -> Result<Vec<u8>> {
+    let body = message.body()?;
+    let crlf = body
+        .iter()
+        .position(|&c| c == b'\n')
+        .is_some_and(|ix| ix > 0 && body[ix - 1] == b'\r');
+    let eol: &[u8] = if crlf { b"\r\n" } else { b"\n" };
+    let sender = message
+        .authors()
+        .next()
+        .map_or("MAILER-DAEMON".to_string(), |r| r.to_string());
+
+    let mut record = format!("From {} {}", sender.replace(' ', "_"), asctime(secs)).into_bytes();
+    record.extend_from_slice(eol);
+    for line in body.split_inclusive(|&c| c == b'\n') {
+        let gt = line.iter().take_while(|&&c| c == b'>').count();
+        if line[gt..].starts_with(b"From ") {
+            record.push(b'>');
+        }
+        record.extend_from_slice(line);
+    }
+    if !body.ends_with(b"\n") {
+        record.extend_from_slice(eol);
+    }
+    record.extend_from_slice(eol);
+    Ok(record)
+}
+
+fn same_file(file: &File, path: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let opened = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(opened.dev() == current.dev() && opened.ino() == current.ino()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err).with_context(|| format!("checking {:?}", path)),
+    }
+}
+
+fn gz(path: &Path) -> PathBuf {
+    with_suffix(path, ".gz")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    name.into()
+}
+
+// Waits for any writer still holding the file, and only removes it once the .gz is complete.
+fn compress(path: &Path) -> Result<()> {
+    let mut file = File::open(path)?;
+    file.lock()?;
+    if !same_file(&file, path)? {
+        // Compressed by someone else while we waited.
+        return Ok(());
+    }
+    let target = gz(path);
+    let tmp = with_suffix(&target, ".tmp");
+    let mut encoder = GzEncoder::new(File::create(&tmp)?, Compression::default());
+    let result = io::copy(&mut file, &mut encoder)
+        .and_then(|_| encoder.finish())
+        .and_then(|f| f.sync_all())
+        .and_then(|_| fs::rename(&tmp, &target));
+    if let Err(err) = result {
+        _ = fs::remove_file(&tmp);
+        return Err(err.into());
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+// ((year, month, day), (hour, minute, second), weekday) in UTC, weekday 0 being Sunday.
+fn civil(secs: u64) -> ((i64, u32, u32), (u64, u64, u64), usize) {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Howard Hinnant's days_from_civil, inverted.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (
+        (y, m, d),
+        (rem / 3600, rem % 3600 / 60, rem % 60),
+        (days + 4).rem_euclid(7) as usize,
+    )
+}
+
+// The date as on a "From " line, e.g. "Sun Oct 18 07:48:07 2026".
+fn asctime(secs: u64) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let ((y, m, d), (hh, mm, ss), wd) = civil(secs);
+    format!(
+        "{} {} {:2} {:02}:{:02}:{:02} {}",
+        DAYS[wd],
+        MONTHS[m as usize - 1],
+        d,
+        hh,
+        mm,
+        ss,
+        y
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::{collections::HashSet, env, io::Read, process};
+
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Dir {
+            let dir = env::temp_dir().join(format!("recogedor-mbox-{}-{}", name, process::id()));
+            _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Dir(dir)
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Tue Nov 14 22:13:20 2023.
+    const SECS: u64 = 1_700_000_000;
+
+    fn record_of(body: &str) -> String {
+        let message = Message::from_rfc822(1, body.as_bytes().to_vec(), HashSet::new());
+        String::from_utf8(record(&message, SECS).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn records() {
+        assert_eq!(
+            record_of("From: fox@den.com\n\nFrom here\n>From there\n>>From x\nFromage\n"),
+            "From fox@den.com Tue Nov 14 22:13:20 2023\n\
+             From: fox@den.com\n\n>From here\n>>From there\n>>>From x\nFromage\n\n"
+        );
+        assert_eq!(
+            record_of("Subject: hi\r\n\r\nFrom a\r\nend"),
+            "From MAILER-DAEMON Tue Nov 14 22:13:20 2023\r\n\
+             Subject: hi\r\n\r\n>From a\r\nend\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(asctime(0), "Thu Jan  1 00:00:00 1970");
+        assert_eq!(asctime(951_782_400), "Tue Feb 29 00:00:00 2000");
+        assert_eq!(asctime(4_107_542_399), "Sun Feb 28 23:59:59 2100");
+        assert_eq!(civil(SECS), ((2023, 11, 14), (22, 13, 20), 2));
+    }
+
+    #[test]
+    fn periods() {
+        for (rotate, period) in [
+            (Rotate::Yearly, "2023"),
+            (Rotate::Monthly, "2023-11"),
+            (Rotate::Daily, "2023-11-14"),
+        ] {
+            assert_eq!(rotate.period(SECS), period);
+            let pattern = Regex::new(&format!("^{}$", rotate.pattern())).unwrap();
+            assert!(pattern.is_match(period));
+        }
+    }
+
+    #[test]
+    fn rotates_and_compresses() {
+        let dir = Dir::new("rotate");
+        let d = &dir.0;
+        let mut table = toml::Table::new();
+        table.insert("path".into(), d.to_str().unwrap().into());
+        table.insert("rotate".into(), "monthly".into());
+        table.insert("max_size".into(), 1.into());
+        table.insert("compress".into(), true.into());
+        let client = MboxEndpoint::from_config("archive", &table)
+            .unwrap()
+            .connect();
+
+        let (mut file, path, fresh) = client.open(d, "INBOX", SECS).unwrap();
+        assert_eq!(path, d.join("INBOX-2023-11.mbox"));
+        assert!(fresh);
+        file.write_all(b"one").unwrap();
+        drop(file);
+        let (_, current, fresh) = client.open(d, "INBOX", SECS).unwrap();
+        assert_eq!(current, path);
+        assert!(fresh);
+        assert_eq!(fs::read(d.join("INBOX-2023-11.1.mbox")).unwrap(), b"one");
+
+        // A crashed compression with its original gone, and one with it still there.
+        fs::write(d.join("INBOX-2023-10.mbox.gz.tmp"), "junk").unwrap();
+        fs::write(d.join("INBOX-2023-11.2.mbox"), "two").unwrap();
+        fs::write(d.join("INBOX-2023-11.2.mbox.gz.tmp"), "junk").unwrap();
+        client.compress(d, "INBOX", &current).unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(d)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "INBOX-2023-11.1.mbox.gz",
+                "INBOX-2023-11.2.mbox.gz",
+                "INBOX-2023-11.mbox"
+            ]
+        );
+        for (name, body) in [
+            ("INBOX-2023-11.1.mbox.gz", "one"),
+            ("INBOX-2023-11.2.mbox.gz", "two"),
+        ] {
+            let mut text = String::new();
+            GzDecoder::new(File::open(d.join(name)).unwrap())
+                .read_to_string(&mut text)
+                .unwrap();
+            assert_eq!(text, body);
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::secret::Secret;
+
+// Access tokens are refreshed when they have less than this left, so one never expires between
+// being handed out and the server checking it.
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+// For token endpoints which don't say.
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy)]
+pub(crate) enum Mechanism {
+    XOAuth2,
+    OAuthBearer,
+}
+
+impl Mechanism {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Mechanism::XOAuth2 => "XOAUTH2",
+            Mechanism::OAuthBearer => "OAUTHBEARER",
+        }
+    }
+}
+
+/// OAuth 2 credentials: a refresh token, exchanged at token_url for short-lived access tokens.
+#[derive(Clone)]
+pub(crate) struct OAuth2 {
+    pub(crate) mechanism: Mechanism,
+    token_url: String,
+    client_id: String,
+    client_secret: Option<Secret>,
+    refresh_token: Secret,
+    // Shared between clones of an endpoint, so every connection uses the same token.
+    cache: Arc<Mutex<Cache>>,
+    // Held across a refresh, so connections needing a token at once don't each fetch one, and a
+    // rotated refresh token isn't used after it's been replaced.
+    refreshing: Arc<tokio::sync::Mutex<()>>,
+}
+
+#[derive(Default)]
+struct Cache {
+    // Set once the provider has rotated the configured refresh token.  Only kept in memory, so lost
+    // on restart.
+    refresh_token: Option<String>,
+    access: Option<(String, Instant)>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+    // Some providers rotate refresh tokens.
+    refresh_token: Option<String>,
+}
+
+impl OAuth2 {
+    pub(crate) fn from_config(
+        name: &str,
+        mechanism: Mechanism,
+        table: &toml::Table,
+    ) -> Result<OAuth2> {
+        let string = |key: &str| -> Result<Option<String>> {
+            match table.get(key) {
+                Some(v) => Ok(Some(
+                    v.as_str()
+                        .with_context(|| format!("{} {} not string", name, key))?
+                        .to_string(),
+                )),
+                None => Ok(None),
+            }
+        };
+        let required = |key: &str| -> Result<String> {
+            string(key)?.with_context(|| format!("{} missing {} for oauth2", name, key))
+        };
+
+        Ok(OAuth2 {
+            mechanism,
+            token_url: required("token_url")?,
+            client_id: required("client_id")?,
+            client_secret: Secret::from_config(name, table, "client_secret")?,
+            refresh_token: Secret::from_config(name, table, "refresh_token")?
+                .with_context(|| format!("{} missing refresh_token for oauth2", name))?,
+            cache: Arc::default(),
+            refreshing: Arc::default(),
+        })
+    }
+
+    /// A current access token, refreshing it first if it's expired or about to.
+    pub(crate) async fn access_token(&self) -> Result<String> {
+        if let Some(token) = self.cached() {
+            return Ok(token);
+        }
+        let _refreshing = self.refreshing.lock().await;
+        // Someone else may have refreshed it while we waited.
+        if let Some(token) = self.cached() {
+            return Ok(token);
+        }
+        let refresh_token = self.cache.lock().unwrap().refresh_token.clone();
+        let refresh_token = match refresh_token {
+            Some(token) => token,
+            None => self
+                .refresh_token
+                .resolve()
+                .await
+                .context("getting refresh_token")?,
+        };
+        let client_secret = match &self.client_secret {
+            Some(secret) => Some(secret.resolve().await.context("getting client_secret")?),
+            None => None,
+        };
+
+        debug!("refreshing access token at {} ...", self.token_url);
+        let mut form = vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", &refresh_token),
+            ("client_id", &self.client_id),
+        ];
+        if let Some(secret) = &client_secret {
+            form.push(("client_secret", secret));
+        }
+        let response = reqwest::Client::new()
+            .post(&self.token_url)
+            .form(&form)
+            .send()
+            .await
+            .with_context(|| format!("requesting token from {}", self.token_url))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            bail!("token endpoint returned {}: {}", status, body.trim());
+        }
+        let token: TokenResponse = response
+            .json()
+            .await
+            .context("token endpoint response malformed")?;
+
+        let lifetime = token
+            .expires_in
+            .map_or(DEFAULT_LIFETIME, Duration::from_secs);
+        let mut cache = self.cache.lock().unwrap();
+        if token.refresh_token.is_some() && token.refresh_token != Some(refresh_token) {
+            info!(
+                "token endpoint rotated the refresh token; the new one is only kept until restart"
+            );
+            cache.refresh_token = token.refresh_token;
+        }
+        cache.access = Some((token.access_token.clone(), Instant::now() + lifetime));
+        Ok(token.access_token)
+    }
+
+    // The cached access token, if it's not about to expire.
+    fn cached(&self) -> Option<String> {
+        let cache = self.cache.lock().unwrap();
+        let (token, expires) = cache.access.as_ref()?;
+        (Instant::now() + REFRESH_MARGIN < *expires).then(|| token.clone())
+    }
+
+    /// Forgets the access token, e.g. because the server rejected it early.
+    pub(crate) fn invalidate(&self) {
+        self.cache.lock().unwrap().access = None;
+    }
+
+    /// server is the host and port connected to, if it was over TCP.
+    pub(crate) fn authenticator(
+        &self,
+        user: &str,
+        server: Option<(&str, u16)>,
+        token: &str,
+    ) -> Authenticator {
+        let response = match self.mechanism {
+            Mechanism::XOAuth2 => format!("user={}\x01auth=Bearer {}\x01\x01", user, token),
+            Mechanism::OAuthBearer => {
+                let server = match server {
+                    Some((host, port)) => format!("host={}\x01port={}\x01", host, port),
+                    None => String::new(),
+                };
+                format!(
+                    "n,a={},\x01{}auth=Bearer {}\x01\x01",
+                    user.replace('=', "=3D").replace(',', "=2C"),
+                    server,
+                    token
+                )
+            }
+        };
+        Authenticator {
+            response: Some(response),
+        }
+    }
+}
+
+/// Answers the server's first challenge with the bearer token.  If the server rejects it, it
+/// sends a second challenge with the details, which must be answered with an empty response
+/// before it gives its final NO.
+pub(crate) struct Authenticator {
+    response: Option<String>,
+}
+
+impl async_imap::Authenticator for Authenticator {
+    type Response = String;
+
+    fn process(&mut self, challenge: &[u8]) -> String {
+        match self.response.take() {
+            Some(response) => response,
+            None => {
+                warn!(
+                    "oauth2 rejected: {}",
+                    String::from_utf8_lossy(challenge).trim()
+                );
+                String::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    // A token endpoint answering a request with each of responses in turn, then refusing
+    // connections.  Returns its URL and the forms it was sent.
+    async fn endpoint(responses: Vec<&'static str>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let forms = Arc::<Mutex<Vec<String>>>::default();
+        let sent = forms.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).await.unwrap();
+                sent.lock().unwrap().push(String::from_utf8(body).unwrap());
+                let reply = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                );
+                stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+        (url, forms)
+    }
+
+    fn oauth(url: &str) -> OAuth2 {
+        let table = format!(
+            "token_url = {:?}\nclient_id = \"fox\"\nrefresh_token = \"r1\"",
+            url
+        );
+        OAuth2::from_config("src", Mechanism::XOAuth2, &table.parse().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn refresh() {
+        let (url, forms) = endpoint(vec![r#"{"access_token": "a1", "expires_in": 3600}"#]).await;
+        let oauth = oauth(&url);
+        assert_eq!(oauth.access_token().await.unwrap(), "a1");
+        // Cached, and shared with clones.
+        assert_eq!(oauth.clone().access_token().await.unwrap(), "a1");
+        let forms = forms.lock().unwrap();
+        assert_eq!(forms.len(), 1);
+        assert!(forms[0].contains("grant_type=refresh_token"));
+        assert!(forms[0].contains("refresh_token=r1"));
+        assert!(forms[0].contains("client_id=fox"));
+    }
+
+    #[tokio::test]
+    async fn expiry() {
+        let (url, forms) = endpoint(vec![
+            r#"{"access_token": "a1", "expires_in": 60}"#,
+            r#"{"access_token": "a2"}"#,
+            r#"{"access_token": "a3"}"#,
+        ])
+        .await;
+        let oauth = oauth(&url);
+        assert_eq!(oauth.access_token().await.unwrap(), "a1");
+        // Within REFRESH_MARGIN of expiring, so refreshed.
+        assert_eq!(oauth.access_token().await.unwrap(), "a2");
+        // No expires_in: good for DEFAULT_LIFETIME.
+        assert_eq!(oauth.access_token().await.unwrap(), "a2");
+        oauth.invalidate();
+        assert_eq!(oauth.access_token().await.unwrap(), "a3");
+        assert_eq!(forms.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn rotation() {
+        let (url, forms) = endpoint(vec![
+            r#"{"access_token": "a1", "expires_in": 0, "refresh_token": "r2"}"#,
+            r#"{"access_token": "a2", "expires_in": 0}"#,
+            r#"{"access_token": "a3", "expires_in": 0}"#,
+        ])
+        .await;
+        let oauth = oauth(&url);
+        assert_eq!(oauth.access_token().await.unwrap(), "a1");
+        assert_eq!(oauth.access_token().await.unwrap(), "a2");
+        // Still the rotated one when the response doesn't give another.
+        assert_eq!(oauth.access_token().await.unwrap(), "a3");
+        let forms = forms.lock().unwrap();
+        assert!(forms[0].contains("refresh_token=r1"));
+        assert!(forms[1].contains("refresh_token=r2"));
+        assert!(forms[2].contains("refresh_token=r2"));
+    }
+
+    #[tokio::test]
+    async fn concurrent() {
+        let (url, forms) = endpoint(vec![
+            r#"{"access_token": "a1", "expires_in": 3600, "refresh_token": "r2"}"#,
+        ])
+        .await;
+        let oauth = oauth(&url);
+        // Only one refresh; the endpoint would refuse a second.
+        let (a, b) = tokio::join!(oauth.access_token(), oauth.access_token());
+        assert_eq!(a.unwrap(), "a1");
+        assert_eq!(b.unwrap(), "a1");
+        assert_eq!(forms.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use log::info;
+
+use crate::config::Config;
+use crate::recorder::Recorder;
+use crate::state;
+
+const MAX_WIDTH: usize = 40;
+
+/// Runs the script against the live source without changing anything: folders are EXAMINEd, and
+/// flags, deletions and appends are recorded instead of made.  Prints what would be done to each
+/// mail item the next run would look at.
+pub(crate) async fn run(config: &Config) -> Result<()> {
+    // The state is opened read-only, so remember! only goes to memory, where seen? still sees
+    // what this run would have remembered.
+    let state = config.state.as_ref();
+
+    for folder in &config.folders {
+        let recorder = Recorder::new();
+        let inner = config
+            .src
+            .connect_source()
+            .await
+            .context("connecting source")?;
+        let mut src = recorder.source(Some(inner));
+        let mailbox = src.examine(folder).await.context("examining folder")?;
+        info!("[{}] examined", folder);
+
+        let min_uid = if config.full_rescan {
+            1
+        } else {
+            state::last_uid(state, folder, None, mailbox.uid_validity).map_or(1, |uid| uid + 1)
+        };
+
+        let mut rows = vec![[
+            "UID".to_string(),
+            "SUBJECT".to_string(),
+            "RECIPIENTS".to_string(),
+            "ACTIONS".to_string(),
+        ]];
+        for mail in src
+            .read(min_uid, config.ir.search())
+            .await
+            .context("reading")?
+        {
+            let handled =
+                state.is_some_and(|state| state.handled(folder, mailbox.uid_validity, mail.uid));
+            let actions = if handled {
+                "skipped (remembered)".to_string()
+            } else {
+                let actions = recorder
+                    .run(
+                        &config.ir,
+                        folder,
+                        mailbox.uid_validity,
+                        state,
+                        &mail,
+                        &mut src,
+                    )
+                    .await
+                    .with_context(|| format!("running script on uid {}", mail.uid))?;
+                if actions.is_empty() {
+                    "nothing".to_string()
+                } else {
+                    actions
+                        .iter()
+                        .map(|a| a.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }
+            };
+
+            let mut recipients: Vec<_> = mail.recipients().map(|r| r.to_string()).collect();
+            recipients.sort();
+            rows.push([
+                mail.uid.to_string(),
+                truncate(mail.subject.as_deref().unwrap_or("")),
+                truncate(&recipients.join(", ")),
+                actions,
+            ]);
+        }
+        src.disconnect().await.context("logging out")?;
+
+        println!("[{}]", folder);
+        print_table(&rows);
+        println!();
+    }
+
+    Ok(())
+}
+
+fn truncate(s: &str) -> String {
+    if s.chars().count() <= MAX_WIDTH {
+        s.to_string()
+    } else {
+        let mut t: String = s.chars().take(MAX_WIDTH - 1).collect();
+        t.push('…');
+        t
+    }
+}
+
+fn print_table(rows: &[[String; 4]]) {
+    let mut widths = [0; 4];
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    for row in rows {
+        let mut line = String::new();
+        for (ix, cell) in row.iter().enumerate() {
+            if ix == row.len() - 1 {
+                line.push_str(cell);
+            } else {
+                let pad = widths[ix] - cell.chars().count();
+                line.push_str(cell);
+                line.push_str(&" ".repeat(pad + 2));
+            }
+        }
+        println!("{}", line);
+    }
+}
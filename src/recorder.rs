@@ -0,0 +1,261 @@
+use anyhow::{bail, Context, Error, Result};
+use async_trait::async_trait;
+use std::{
+    env, fmt, fs,
+    path::PathBuf,
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::endpoint::{
+    DestinationEndpoint, EndpointFlagger, EndpointReader, EndpointSelector, EndpointWriter,
+    IdleResult, Mailbox, Message, SourceEndpoint,
+};
+use crate::ir::{SearchKey, IR};
+use crate::shutdown::Shutdown;
+use crate::state::State;
+
+// Fake endpoints which write down what the script asks of them instead of doing it, so a script
+// can be run against mail without touching any server.
+
+const UID_VALIDITY: u32 = 1;
+
+#[derive(Clone, PartialEq)]
+pub(crate) enum Action {
+    Append(String),
+    Flag(String),
+    Delete,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Append(dest) => write!(f, "(append! {:?})", dest),
+            Action::Flag(fl) => write!(f, "(flag! {:?})", fl),
+            Action::Delete => f.write_str("(delete!)"),
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Action> {
+        let sexp = lexpr::from_str(s).with_context(|| format!("can't parse action {:?}", s))?;
+        let vec = sexp.to_vec().unwrap_or_default();
+        let arg = || vec.get(1).and_then(|v| v.as_str()).map(str::to_string);
+        match (vec.first().and_then(|v| v.as_symbol()), vec.len()) {
+            (Some("append!"), 2) => Ok(Action::Append(
+                arg().context("append! expects a destination string")?,
+            )),
+            (Some("flag!"), 2) => Ok(Action::Flag(arg().context("flag! expects a flag string")?)),
+            (Some("delete!"), 1) => Ok(Action::Delete),
+            _ => bail!(
+                "unknown action {:?}; expected (append! D), (flag! F) or (delete!)",
+                s
+            ),
+        }
+    }
+}
+
+type Log = Arc<Mutex<Vec<Action>>>;
+
+/// Hands out recording endpoints which all write to one log.
+pub(crate) struct Recorder {
+    log: Log,
+}
+
+impl Recorder {
+    pub(crate) fn new() -> Recorder {
+        Recorder {
+            log: Log::default(),
+        }
+    }
+
+    /// A source which records flags and deletions.  Everything else goes to inner, with folders
+    /// only ever EXAMINEd; without inner, mail must be handed over with its body loaded.
+    pub(crate) fn source(&self, inner: Option<Box<dyn SourceEndpoint>>) -> Box<dyn SourceEndpoint> {
+        Box::new(RecordingSource {
+            log: self.log.clone(),
+            inner,
+        })
+    }
+
+    /// Runs the script on mail, returning what it would have done.
+    pub(crate) async fn run(
+        &self,
+        ir: &IR,
+        folder: &str,
+        uid_validity: u32,
+        state: Option<&State>,
+        mail: &Message,
+        src: &mut Box<dyn SourceEndpoint>,
+    ) -> Result<Vec<Action>> {
+        self.log.lock().unwrap().clear();
+        let dests = ir
+            .dests()
+            .iter()
+            .map(|dest| -> Box<dyn DestinationEndpoint> {
+                Box::new(RecordingDestination {
+                    name: dest.name().to_string(),
+                    log: self.log.clone(),
+                })
+            })
+            .collect();
+
+        let mut closure = ir
+            .closure(folder, uid_validity, state)
+            .with_destinations(dests);
+        closure.process(mail, src).await?;
+        closure.finish().await?;
+
+        let actions = self.log.lock().unwrap().drain(..).collect();
+        Ok(actions)
+    }
+}
+
+/// Runs the script on mail as if it were in folder, without any real endpoints.
+pub(crate) async fn simulate(
+    ir: &IR,
+    folder: &str,
+    state: Option<&State>,
+    mail: &Message,
+) -> Result<Vec<Action>> {
+    let recorder = Recorder::new();
+    let mut src = recorder.source(None);
+    recorder
+        .run(ir, folder, UID_VALIDITY, state, mail, &mut src)
+        .await
+}
+
+struct RecordingSource {
+    log: Log,
+    inner: Option<Box<dyn SourceEndpoint>>,
+}
+
+#[async_trait]
+impl EndpointSelector for RecordingSource {
+    async fn select(&mut self, folder: &str) -> Result<Mailbox> {
+        self.examine(folder).await
+    }
+
+    async fn examine(&mut self, folder: &str) -> Result<Mailbox> {
+        match &mut self.inner {
+            Some(inner) => inner.examine(folder).await,
+            None => Ok(Mailbox {
+                uid_validity: UID_VALIDITY,
+            }),
+        }
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.disconnect().await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl EndpointReader for RecordingSource {
+    async fn idle(&mut self, _shutdown: &mut Shutdown) -> Result<IdleResult> {
+        Ok(IdleResult::Shutdown)
+    }
+
+    async fn read(&mut self, min_uid: u32, search: Option<&SearchKey>) -> Result<Vec<Message>> {
+        match &mut self.inner {
+            Some(inner) => inner.read(min_uid, search).await,
+            None => Ok(vec![]),
+        }
+    }
+
+    async fn fetch_body(&mut self, uid: u32) -> Result<Vec<u8>> {
+        match &mut self.inner {
+            Some(inner) => inner.fetch_body(uid).await,
+            None => bail!("no body for {}", uid),
+        }
+    }
+}
+
+#[async_trait]
+impl EndpointFlagger for RecordingSource {
+    async fn flag(&mut self, _uid: u32, flag: &str) -> Result<()> {
+        self.log
+            .lock()
+            .unwrap()
+            .push(Action::Flag(flag.to_string()));
+        Ok(())
+    }
+
+    async fn delete(&mut self, _uid: u32) -> Result<()> {
+        self.log.lock().unwrap().push(Action::Delete);
+        Ok(())
+    }
+
+    async fn expunge(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct RecordingDestination {
+    name: String,
+    log: Log,
+}
+
+#[async_trait]
+impl EndpointSelector for RecordingDestination {
+    async fn select(&mut self, _folder: &str) -> Result<Mailbox> {
+        Ok(Mailbox {
+            uid_validity: UID_VALIDITY,
+        })
+    }
+
+    async fn examine(&mut self, folder: &str) -> Result<Mailbox> {
+        self.select(folder).await
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EndpointWriter for RecordingDestination {
+    async fn append(&mut self, _folder: &str, _message: &Message) -> Result<()> {
+        self.log
+            .lock()
+            .unwrap()
+            .push(Action::Append(self.name.to_string()));
+        Ok(())
+    }
+}
+
+/// A state in a temporary directory, for running scripts which use seen? or remember! without
+/// touching the real state_dir.  Removed when dropped.
+pub(crate) struct ScratchState {
+    dir: PathBuf,
+    pub(crate) state: State,
+}
+
+impl ScratchState {
+    pub(crate) fn open() -> Result<ScratchState> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "recogedor-test-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let state = State::open(&dir).context("opening scratch state")?;
+        Ok(ScratchState { dir, state })
+    }
+}
+
+impl Drop for ScratchState {
+    fn drop(&mut self) {
+        _ = fs::remove_dir_all(&self.dir);
+    }
+}
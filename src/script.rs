@@ -1,15 +1,74 @@
-use anyhow::Result;
+use anyhow::{anyhow, Error, Result};
 use std::{collections::hash_map::HashMap, str};
 
-use crate::ast::Stmt;
+use crate::ast::{Origin, Stmt, SyntaxError};
 use crate::endpoint::Endpoint;
 use crate::ir::IR;
 
-pub(crate) fn compile(text: &str, dests: HashMap<String, Endpoint>) -> Result<IR> {
-    let parser = lexpr::Parser::from_reader(text.as_bytes());
+pub(crate) fn compile(text: &str, origin: &Origin, dests: HashMap<String, Endpoint>) -> Result<IR> {
+    let mut parser = lexpr::Parser::from_str(text);
     let mut stmts = vec![];
-    for sexp in parser {
-        stmts.push(Stmt::from_sexp(&sexp?)?);
+    for datum in parser.datum_iter() {
+        let datum = datum.map_err(|err| snippet(origin, origin.parse_error(&err).into()))?;
+        stmts.push(Stmt::from_datum(datum.as_ref(), origin).map_err(|err| snippet(origin, err))?);
     }
-    IR::compile(&stmts, dests)
+    IR::compile(&stmts, dests).map_err(|err| snippet(origin, err))
+}
+
+// Adds the offending line of the config file, with the span marked, to syntax errors:
+//
+//   `append!` expects a destination string at line 7 col 14, got symbol `fox`
+//      |
+//    7 |     (append! fox)
+//      |              ^^^
+fn snippet(origin: &Origin, err: Error) -> Error {
+    let Some(se) = err.downcast_ref::<SyntaxError>() else {
+        return err;
+    };
+    let (start, end) = (se.span.start, se.span.end);
+    let Some(line) = origin.line(start.line) else {
+        return err;
+    };
+    // Columns are bytes; the marker goes by characters.
+    let chars = |from: usize, to: usize| line.get(from..to).map_or(0, |s| s.chars().count());
+    let col = start.col.saturating_sub(1).min(line.len());
+    let width = if end.line == start.line {
+        chars(col, end.col.saturating_sub(1).min(line.len()))
+    } else {
+        chars(col, line.len())
+    }
+    .max(1);
+
+    let gutter = " ".repeat(start.line.to_string().len());
+    let mut message = format!(
+        "{}\n{} |\n{} | {}\n{} | {}{}",
+        se,
+        gutter,
+        start.line,
+        line,
+        gutter,
+        " ".repeat(chars(0, col)),
+        "^".repeat(width)
+    );
+    if origin.relative() {
+        message.push_str(&format!(
+            "\n{} = line and column are within the script, not the config file",
+            gutter
+        ));
+    }
+    anyhow!(message)
+}
+
+/// Compiles a script with maildir stand-ins for the destinations it names, for tests.
+#[cfg(test)]
+pub(crate) fn compile_for_test(text: &str, dests: &[&str]) -> Result<IR> {
+    let dests = dests
+        .iter()
+        .map(|&name| {
+            let config: toml::Value =
+                toml::from_str("type = \"maildir\"\npath = \"/nonexistent\"")?;
+            Ok((name.to_string(), Endpoint::from_config(name, &config)?))
+        })
+        .collect::<Result<_>>()?;
+    compile(text, &Origin::script(text), dests)
 }
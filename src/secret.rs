@@ -0,0 +1,86 @@
+use anyhow::{bail, Context, Result};
+use std::{env, path::PathBuf, process::Stdio};
+use tokio::{fs, process::Command};
+
+/// A credential from the config: given literally, or read from a file, a command's output or an
+/// environment variable each time it's needed, so it can be rotated without a restart.
+#[derive(Clone)]
+pub(crate) enum Secret {
+    Literal(String),
+    File(PathBuf),
+    Command(String),
+    Env(String),
+}
+
+impl Secret {
+    /// Reads key, or one of key_file, key_command and key_env, from table.
+    pub(crate) fn from_config(
+        name: &str,
+        table: &toml::Table,
+        key: &str,
+    ) -> Result<Option<Secret>> {
+        let mut found = vec![];
+        for (suffix, make) in [
+            ("", Secret::Literal as fn(String) -> Secret),
+            ("_file", |s: String| Secret::File(s.into())),
+            ("_command", Secret::Command),
+            ("_env", Secret::Env),
+        ] {
+            let k = format!("{}{}", key, suffix);
+            if let Some(v) = table.get(&k) {
+                let s = v
+                    .as_str()
+                    .with_context(|| format!("{} {} not string", name, k))?;
+                found.push((k, make(s.to_string())));
+            }
+        }
+
+        if found.len() > 1 {
+            let keys: Vec<_> = found.iter().map(|(k, _)| k.as_str()).collect();
+            bail!("{} has more than one of {}", name, keys.join(", "));
+        }
+        Ok(found.pop().map(|(_, secret)| secret))
+    }
+
+    pub(crate) async fn resolve(&self) -> Result<String> {
+        match self {
+            Secret::Literal(s) => Ok(s.clone()),
+            Secret::File(path) => {
+                let text = fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("reading secret from {:?}", path))?;
+                Ok(chomp(&text).to_string())
+            }
+            // Only the first line counts, as `pass show` puts other details after it.
+            Secret::Command(cmd) => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(cmd)
+                    .stdin(Stdio::null())
+                    .output()
+                    .await
+                    .with_context(|| format!("running {:?}", cmd))?;
+                if !output.status.success() {
+                    bail!(
+                        "{:?} failed ({}): {}",
+                        cmd,
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                }
+                let stdout = String::from_utf8(output.stdout)
+                    .with_context(|| format!("{:?} output not UTF-8", cmd))?;
+                Ok(stdout.lines().next().unwrap_or("").to_string())
+            }
+            Secret::Env(var) => {
+                env::var(var).with_context(|| format!("reading secret from ${}", var))
+            }
+        }
+    }
+}
+
+// A trailing newline is almost never part of the secret; editors add one.
+fn chomp(s: &str) -> &str {
+    let s = s.strip_suffix('\n').unwrap_or(s);
+    s.strip_suffix('\r').unwrap_or(s)
+}
@@ -0,0 +1,30 @@
+use tokio::sync::watch;
+
+// Shared by every folder; once triggered, folders finish the mail item they're on, tidy up and
+// log out.
+#[derive(Clone)]
+pub(crate) struct Shutdown(watch::Receiver<bool>);
+
+pub(crate) struct Trigger(watch::Sender<bool>);
+
+pub(crate) fn channel() -> (Trigger, Shutdown) {
+    let (tx, rx) = watch::channel(false);
+    (Trigger(tx), Shutdown(rx))
+}
+
+impl Trigger {
+    pub(crate) fn trigger(&self) {
+        _ = self.0.send(true);
+    }
+}
+
+impl Shutdown {
+    pub(crate) fn requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    pub(crate) async fn wait(&mut self) {
+        // An error means the Trigger was dropped, which only happens on exit anyway.
+        _ = self.0.wait_for(|&requested| requested).await;
+    }
+}
@@ -0,0 +1,384 @@
+use anyhow::{bail, Context, Result};
+use log::warn;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::endpoint::Message;
+
+// The seen log is append-only, one record per line:
+//
+//   U <uidvalidity> <uid> <folder>
+//   M <message-id>
+//
+// Folder names can contain spaces, so they come last.
+//
+// The last-uid file is rewritten whole whenever it changes, one line per folder:
+//
+//   <uidvalidity> <uid> <folder>
+
+pub(crate) struct State {
+    path: PathBuf,
+    last_uid_path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    // None when opened read-only, so only remembering in memory.
+    file: Option<File>,
+    uids: HashSet<(String, u32, u32)>,
+    message_ids: HashSet<Vec<u8>>,
+    last_uids: HashMap<String, (u32, u32)>,
+}
+
+impl State {
+    pub(crate) fn open<P: AsRef<Path>>(dir: P) -> Result<State> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).with_context(|| format!("creating state dir {:?}", dir))?;
+        let path = dir.join("seen");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening {:?} for append", path))?;
+
+        // Records are written under the lock, so one without its newline wasn't cut short by
+        // anyone still writing it, but by a crash; it never got remembered, so drop it rather than
+        // refuse to start, and so the next record starts on its own line.
+        file.lock().with_context(|| format!("locking {:?}", path))?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)
+            .with_context(|| format!("reading {:?}", path))?;
+        let len = complete(&data);
+        if len < data.len() {
+            warn!(
+                "{:?} ends with an incomplete record {:?}; dropping it",
+                path,
+                String::from_utf8_lossy(&data[len..])
+            );
+            file.set_len(len as u64)
+                .and_then(|_| file.sync_all())
+                .with_context(|| format!("truncating {:?}", path))?;
+            data.truncate(len);
+        }
+        file.unlock()
+            .with_context(|| format!("unlocking {:?}", path))?;
+
+        Self::load(dir, &data, Some(file))
+    }
+
+    /// The state for looking at without changing: nothing is created, truncated or written, and
+    /// anything remembered is only remembered in memory.
+    pub(crate) fn open_read_only<P: AsRef<Path>>(dir: P) -> Result<State> {
+        let dir = dir.as_ref();
+        let path = dir.join("seen");
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err).with_context(|| format!("reading {:?}", path)),
+        };
+        // An incomplete record may still be being written, so it's only ignored.
+        let len = complete(&data);
+        Self::load(dir, &data[..len], None)
+    }
+
+    fn load(dir: &Path, data: &[u8], file: Option<File>) -> Result<State> {
+        let path = dir.join("seen");
+        let mut uids = HashSet::new();
+        let mut message_ids = HashSet::new();
+        for (lineno, line) in data.split(|&c| c == b'\n').enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            Self::parse_line(line, &mut uids, &mut message_ids)
+                .with_context(|| format!("{:?} line {} malformed", path, lineno + 1))?;
+        }
+
+        let last_uid_path = dir.join("last-uid");
+        let mut last_uids = HashMap::new();
+        if last_uid_path.exists() {
+            let text = fs::read_to_string(&last_uid_path)
+                .with_context(|| format!("reading {:?}", last_uid_path))?;
+            for (lineno, line) in text.lines().enumerate() {
+                let parse_line = || -> Result<(String, (u32, u32))> {
+                    let mut parts = line.splitn(3, ' ');
+                    let uid_validity = parts.next().context("missing uidvalidity")?.parse()?;
+                    let uid = parts.next().context("missing uid")?.parse()?;
+                    let folder = parts.next().context("missing folder")?.to_string();
+                    Ok((folder, (uid_validity, uid)))
+                };
+                let (folder, last_uid) = parse_line().with_context(|| {
+                    format!("{:?} line {} malformed", last_uid_path, lineno + 1)
+                })?;
+                last_uids.insert(folder, last_uid);
+            }
+        }
+
+        Ok(State {
+            path,
+            last_uid_path,
+            inner: Mutex::new(Inner {
+                file,
+                uids,
+                message_ids,
+                last_uids,
+            }),
+        })
+    }
+
+    fn parse_line(
+        line: &[u8],
+        uids: &mut HashSet<(String, u32, u32)>,
+        message_ids: &mut HashSet<Vec<u8>>,
+    ) -> Result<()> {
+        match line.split_first() {
+            Some((b'U', rest)) => {
+                let rest = std::str::from_utf8(rest)?;
+                let mut parts = rest.trim_start().splitn(3, ' ');
+                let uid_validity = parts.next().context("missing uidvalidity")?.parse()?;
+                let uid = parts.next().context("missing uid")?.parse()?;
+                let folder = parts.next().context("missing folder")?.to_string();
+                uids.insert((folder, uid_validity, uid));
+            }
+            Some((b'M', rest)) => {
+                message_ids.insert(rest.trim_ascii_start().to_vec());
+            }
+            _ => bail!("unknown record"),
+        }
+        Ok(())
+    }
+
+    /// Whether this exact message (by folder, UIDVALIDITY and UID) has been remembered.
+    pub(crate) fn handled(&self, folder: &str, uid_validity: u32, uid: u32) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .uids
+            .contains(&(folder.to_string(), uid_validity, uid))
+    }
+
+    /// Whether this message, or another with the same Message-ID, has been remembered.
+    pub(crate) fn seen(&self, folder: &str, uid_validity: u32, mail: &Message) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .uids
+            .contains(&(folder.to_string(), uid_validity, mail.uid))
+            || mail
+                .message_id
+                .as_ref()
+                .is_some_and(|mid| inner.message_ids.contains(mid))
+    }
+
+    pub(crate) fn remember(&self, folder: &str, uid_validity: u32, mail: &Message) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner {
+            file,
+            uids,
+            message_ids,
+            ..
+        } = &mut *inner;
+
+        let mut record = vec![];
+        if uids.insert((folder.to_string(), uid_validity, mail.uid)) {
+            writeln!(record, "U {} {} {}", uid_validity, mail.uid, folder)?;
+        }
+        if let Some(mid) = &mail.message_id {
+            // A Message-ID with a newline in it would corrupt the log; don't record those.
+            if !mid.contains(&b'\n') && message_ids.insert(mid.clone()) {
+                record.extend_from_slice(b"M ");
+                record.extend_from_slice(mid);
+                record.push(b'\n');
+            }
+        }
+
+        if let (Some(file), false) = (file, record.is_empty()) {
+            file.lock()
+                .and_then(|_| {
+                    let written = file.write_all(&record).and_then(|_| file.sync_data());
+                    let unlocked = file.unlock();
+                    written.and(unlocked)
+                })
+                .with_context(|| format!("writing {:?}", self.path))?;
+        }
+        Ok(())
+    }
+
+    /// The highest UID processed in this folder, if its UIDVALIDITY hasn't changed since.
+    pub(crate) fn last_uid(&self, folder: &str, uid_validity: u32) -> Option<u32> {
+        let inner = self.inner.lock().unwrap();
+        match inner.last_uids.get(folder) {
+            Some(&(v, uid)) if v == uid_validity => Some(uid),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn set_last_uid(&self, folder: &str, uid_validity: u32, uid: u32) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.last_uids.get(folder) == Some(&(uid_validity, uid)) {
+            return Ok(());
+        }
+        inner
+            .last_uids
+            .insert(folder.to_string(), (uid_validity, uid));
+        if inner.file.is_none() {
+            return Ok(());
+        }
+
+        let mut text = String::new();
+        for (folder, (uid_validity, uid)) in &inner.last_uids {
+            text.push_str(&format!("{} {} {}\n", uid_validity, uid, folder));
+        }
+        let tmp = self.last_uid_path.with_extension("tmp");
+        fs::write(&tmp, text)
+            .and_then(|_| File::open(&tmp)?.sync_all())
+            .and_then(|_| fs::rename(&tmp, &self.last_uid_path))
+            .with_context(|| format!("writing {:?}", self.last_uid_path))?;
+        Ok(())
+    }
+}
+
+// How much of the seen log is whole records.
+fn complete(data: &[u8]) -> usize {
+    if data.is_empty() || data.ends_with(b"\n") {
+        return data.len();
+    }
+    data.iter()
+        .rposition(|&c| c == b'\n')
+        .map_or(0, |ix| ix + 1)
+}
+
+/// The highest UID processed in a folder: per cursor, this run's UIDVALIDITY and highest UID so
+/// far, if it's still current, and otherwise per the state.
+pub(crate) fn last_uid(
+    state: Option<&State>,
+    folder: &str,
+    cursor: Option<(u32, u32)>,
+    uid_validity: u32,
+) -> Option<u32> {
+    match cursor {
+        Some((v, uid)) if v == uid_validity => Some(uid),
+        _ => state.and_then(|state| state.last_uid(folder, uid_validity)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, process, thread, time::Duration};
+
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Dir {
+            let dir = env::temp_dir().join(format!("recogedor-state-{}-{}", name, process::id()));
+            _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Dir(dir)
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn mail(uid: u32, message_id: &str) -> Message {
+        let body = format!("Message-ID: {}\r\n\r\n", message_id).into_bytes();
+        Message::from_rfc822(uid, body, HashSet::new())
+    }
+
+    #[test]
+    fn remembers() {
+        let dir = Dir::new("remembers");
+        let state = State::open(&dir.0).unwrap();
+        state.remember("INBOX", 7, &mail(1, "<a@b>")).unwrap();
+        state.set_last_uid("INBOX", 7, 1).unwrap();
+        drop(state);
+
+        let state = State::open(&dir.0).unwrap();
+        assert!(state.handled("INBOX", 7, 1));
+        assert!(!state.handled("INBOX", 8, 1));
+        assert!(state.seen("Spam", 9, &mail(5, "<a@b>")));
+        assert_eq!(state.last_uid("INBOX", 7), Some(1));
+        assert_eq!(state.last_uid("INBOX", 8), None);
+    }
+
+    #[test]
+    fn torn_record() {
+        let dir = Dir::new("torn");
+        fs::write(dir.0.join("seen"), "U 7 1 INBOX\nM <a@b>\nU 7 2 IN").unwrap();
+        let state = State::open(&dir.0).unwrap();
+        assert!(state.handled("INBOX", 7, 1));
+        assert!(!state.handled("INBOX", 7, 2));
+        state.remember("INBOX", 7, &mail(3, "<c@d>")).unwrap();
+        drop(state);
+
+        assert_eq!(
+            fs::read_to_string(dir.0.join("seen")).unwrap(),
+            "U 7 1 INBOX\nM <a@b>\nU 7 3 INBOX\nM <c@d>\n"
+        );
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = Dir::new("read-only");
+        let missing = dir.0.join("missing");
+        let state = State::open_read_only(&missing).unwrap();
+        state.remember("INBOX", 7, &mail(1, "<a@b>")).unwrap();
+        state.set_last_uid("INBOX", 7, 1).unwrap();
+        assert!(state.handled("INBOX", 7, 1));
+        assert!(!missing.exists());
+
+        fs::write(dir.0.join("seen"), "U 7 1 INBOX\nU 7 2 IN").unwrap();
+        let state = State::open_read_only(&dir.0).unwrap();
+        assert!(state.handled("INBOX", 7, 1));
+        assert!(!state.handled("INBOX", 7, 2));
+        state.remember("INBOX", 7, &mail(3, "<c@d>")).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.0.join("seen")).unwrap(),
+            "U 7 1 INBOX\nU 7 2 IN"
+        );
+        assert!(!dir.0.join("last-uid").exists());
+    }
+
+    #[test]
+    fn record_in_flight() {
+        let dir = Dir::new("in-flight");
+        let mut writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.0.join("seen"))
+            .unwrap();
+        writer.lock().unwrap();
+        writer.write_all(b"U 7 1 IN").unwrap();
+        let path = dir.0.clone();
+        let opener = thread::spawn(move || State::open(path).unwrap());
+        thread::sleep(Duration::from_millis(50));
+        writer.write_all(b"BOX\n").unwrap();
+        writer.unlock().unwrap();
+
+        assert!(opener.join().unwrap().handled("INBOX", 7, 1));
+        assert_eq!(
+            fs::read_to_string(dir.0.join("seen")).unwrap(),
+            "U 7 1 INBOX\n"
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let dir = Dir::new("malformed");
+        fs::write(dir.0.join("last-uid"), "7 1 INBOX\n7 x Spam\n").unwrap();
+        let err = State::open(&dir.0).err().unwrap();
+        assert!(format!("{:#}", err).contains("line 2 malformed"));
+
+        fs::write(dir.0.join("last-uid"), "").unwrap();
+        fs::write(dir.0.join("seen"), "X nonsense\n").unwrap();
+        let err = State::open(&dir.0).err().unwrap();
+        assert!(format!("{:#}", err).contains("line 1 malformed"));
+    }
+}
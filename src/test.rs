@@ -0,0 +1,67 @@
+use anyhow::{Context, Result};
+use clap::ArgMatches;
+use std::{collections::HashSet, path::PathBuf};
+
+use crate::config::Config;
+use crate::endpoint::Message;
+use crate::fixture;
+use crate::header;
+use crate::recorder::{self, ScratchState};
+
+/// Runs the script on mail from files, with recording endpoints standing in for the real ones, and
+/// prints what it would do with each.
+pub(crate) async fn run(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let flags: HashSet<String> = matches
+        .get_many::<String>("flag")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let recipients: Vec<(String, String)> = matches
+        .get_many::<String>("recipient")
+        .unwrap_or_default()
+        .flat_map(|r| header::addresses(r))
+        .collect();
+    let folder = matches
+        .get_one::<String>("folder")
+        .or(config.folders.first())
+        .map_or("INBOX", |f| f.as_str());
+
+    // seen? and remember! get a scratch state, so mail is only seen if remembered earlier in the
+    // run, and the real state_dir is left alone.
+    let scratch = config
+        .ir
+        .uses_state()
+        .then(ScratchState::open)
+        .transpose()?;
+    let state = scratch.as_ref().map(|s| &s.state);
+
+    let mut uid = 0;
+    for path in matches.get_many::<PathBuf>("FILES").unwrap_or_default() {
+        let messages = fixture::load(path)?;
+        let count = messages.len();
+        for (ix, body) in messages.into_iter().enumerate() {
+            uid += 1;
+            let mut mail = Message::from_rfc822(uid, body, flags.clone());
+            for (mailbox, host) in &recipients {
+                mail.add_recipient(mailbox, host);
+            }
+
+            let actions = recorder::simulate(&config.ir, folder, state, &mail)
+                .await
+                .with_context(|| format!("running script on {}", path.display()))?;
+            if count > 1 {
+                print!("{} #{}", path.display(), ix + 1);
+            } else {
+                print!("{}", path.display());
+            }
+            println!(" ({})", mail.subject.as_deref().unwrap_or("no subject"));
+            if actions.is_empty() {
+                println!("  nothing");
+            }
+            for action in actions {
+                println!("  {}", action);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use anyhow::{Context, Result};
+use std::{
+    io,
+    pin::Pin,
+    process::Stdio,
+    task::{Context as TaskContext, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+
+use crate::imap::{Session, Stream};
+
+/// A command's stdin and stdout as one stream, for IMAP over e.g. ssh.  The command is run by
+/// the shell, and killed when the stream is dropped.
+#[derive(Debug)]
+pub(crate) struct Tunnel {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl Tunnel {
+    pub(crate) fn spawn(cmd: &str) -> Result<Tunnel> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("starting tunnel {:?}", cmd))?;
+        Ok(Tunnel {
+            stdin: child.stdin.take().unwrap(),
+            stdout: child.stdout.take().unwrap(),
+            _child: child,
+        })
+    }
+}
+
+impl AsyncRead for Tunnel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Tunnel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdin).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_shutdown(cx)
+    }
+}
+
+/// A session for a server which sent PREAUTH.  async-imap can only make a session by logging in,
+/// so this logs in to a stand-in which swallows the LOGIN and answers OK, then hands over to the
+/// real server.
+pub(crate) async fn preauthenticated(
+    client: async_imap::Client<Box<dyn Stream>>,
+) -> Result<Session> {
+    // The greeting's been read and the server is waiting for us, so nothing buffered is lost.
+    let stream = client.into_inner();
+    let client = async_imap::Client::new(Box::new(Preauthenticated {
+        inner: stream,
+        // A fresh client's first tag.
+        reply: Some(io::Cursor::new(b"A0001 OK PREAUTH\r\n".to_vec())),
+    }) as Box<dyn Stream>);
+    Ok(client.login("", "").await.map_err(|e| e.0)?)
+}
+
+#[derive(Debug)]
+struct Preauthenticated {
+    inner: Box<dyn Stream>,
+    // While Some, writes are dropped and reads come from here.
+    reply: Option<io::Cursor<Vec<u8>>>,
+}
+
+impl AsyncRead for Preauthenticated {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let Some(reply) = &mut self.reply else {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        };
+        let rest = &reply.get_ref()[reply.position() as usize..];
+        let n = rest.len().min(buf.remaining());
+        buf.put_slice(&rest[..n]);
+        reply.set_position(reply.position() + n as u64);
+        if reply.position() as usize == reply.get_ref().len() {
+            self.reply = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Preauthenticated {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.reply.is_some() {
+            return Poll::Ready(Ok(buf.len()));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        if self.reply.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}